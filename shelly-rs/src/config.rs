@@ -1,30 +1,63 @@
 use std::fs;
 use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::OnceLock;
 use dirs::home_dir;
 
+/// How startup questions are answered, from `--yes`, `--download`,
+/// `--no-download` and `--non-interactive` (or their SHELLY_* variables)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Prompts {
+    /// Answer yes to every question
+    pub assume_yes: bool,
+    /// Download missing toolbox files without asking
+    pub download: bool,
+    /// Skip missing toolbox files without asking
+    pub no_download: bool,
+    /// Never read an answer from stdin; unanswered questions are errors
+    pub non_interactive: bool,
+}
+
+static PROMPTS: OnceLock<Prompts> = OnceLock::new();
+
+/// Set the prompt policy once at startup
+pub fn set_prompts(prompts: Prompts) {
+    PROMPTS.get_or_init(|| prompts);
+}
+
+/// The prompt policy (interactive until `set_prompts` says otherwise)
+pub fn prompts() -> Prompts {
+    PROMPTS.get().copied().unwrap_or_default()
+}
+
 pub fn ensure_exists() -> io::Result<()> {
     let home = home_dir().expect("Could not determine home directory");
     let mut shelly_dir = PathBuf::from(&home);
     shelly_dir.push(".shelly");
 
-    let mut abort = false;
-
     // Create directory if missing (recursively, but it's just one component)
     if !shelly_dir.exists() {
-        if ask_yes_no("Looks like this is your first time running shelly. Init config file (~/.shelly/shelly.json)"){
+        let prompts = prompts();
+        let create = if prompts.assume_yes {
+            true
+        } else if prompts.non_interactive {
+            return Err(io::Error::other(
+                "no config at ~/.shelly/shelly.json; pass --yes to create it",
+            ));
+        } else {
+            ask_yes_no("Looks like this is your first time running shelly. Init config file (~/.shelly/shelly.json)")
+        };
+        if create {
             fs::create_dir_all(&shelly_dir)?;
         } else {
-           println!("Exiting, cannot continue without config file");
-           abort = true;
+            return Err(io::Error::other("Exiting, cannot continue without config file"));
         }
-        
     }
 
     let mut shelly_file = shelly_dir.clone();
     shelly_file.push("shelly.json");
 
-    if !shelly_file.exists() && !abort{
+    if !shelly_file.exists() {
         let mut file = fs::File::create(&shelly_file)?;
         let default_config = r#"{
     "shelly": {
@@ -48,12 +81,81 @@ pub fn ensure_exists() -> io::Result<()> {
     Ok(())
 }
 
+/// Where shelly listens locally versus what payloads tell the target to call
+/// back to. They differ behind port forwards, redirectors and tunnels.
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub bind_host: String,
+    pub port: u16,
+    pub http_port: u16,
+    pub advertise_host: String,
+    pub advertise_port: u16,
+    pub advertise_http_port: u16,
+}
+
+impl Endpoints {
+    /// Endpoints for a listener on `port` from the `shelly` config section
+    /// (`bind_host`, `advertise_host`, `advertise_port`, `advertise_http_port`).
+    /// `host` is the callback address used when no `advertise_host` is set;
+    /// advertised ports default to the local ones.
+    pub fn from_config(config: &json::JsonValue, host: &str, port: u16) -> Self {
+        let shelly = &config["shelly"];
+        let http_port = shelly["default_http_svr"].as_u16().unwrap_or(8000);
+        Endpoints {
+            bind_host: shelly["bind_host"].as_str().unwrap_or("::").to_string(),
+            port,
+            http_port,
+            advertise_host: shelly["advertise_host"].as_str().unwrap_or(host).to_string(),
+            advertise_port: shelly["advertise_port"].as_u16().unwrap_or(port),
+            advertise_http_port: shelly["advertise_http_port"].as_u16().unwrap_or(http_port),
+        }
+    }
+
+    /// Whether listeners bind every interface rather than one address
+    pub fn binds_all(&self) -> bool {
+        matches!(self.bind_host.as_str(), "" | "::" | "0.0.0.0")
+    }
+}
+
+/// Interface picked on the previous run, from ~/.shelly/last_iface
+pub fn last_interface() -> Option<String> {
+    let home = home_dir()?;
+    let name = fs::read_to_string(PathBuf::from(&home).join(".shelly").join("last_iface")).ok()?;
+    let name = name.trim();
+    if name.is_empty() { None } else { Some(name.to_string()) }
+}
+
+/// Remember the chosen interface for the next run
+pub fn remember_interface(name: &str) {
+    let home = home_dir().expect("Could not determine home directory");
+    let path = PathBuf::from(&home).join(".shelly").join("last_iface");
+    if let Err(e) = fs::write(&path, format!("{}\n", name)) {
+        eprintln!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// Re-read shelly.json, or None if it's missing or doesn't parse
+pub fn reload_config() -> Option<json::JsonValue> {
+    let home = home_dir()?;
+    let shelly_config = PathBuf::from(&home).join(".shelly").join("shelly.json");
+    json::parse(&fs::read_to_string(shelly_config).ok()?).ok()
+}
+
+/// Write shelly.json back, keeping the previous version as shelly.json.bak
+pub fn save_config(config: &json::JsonValue) -> io::Result<()> {
+    let home = home_dir().expect("Could not determine home directory");
+    let shelly_config = PathBuf::from(&home).join(".shelly").join("shelly.json");
+    if shelly_config.exists() {
+        fs::copy(&shelly_config, shelly_config.with_extension("json.bak"))?;
+    }
+    fs::write(&shelly_config, format!("{}\n", json::stringify_pretty(config.clone(), 4)))
+}
+
 pub fn load_config() -> json::JsonValue {
     let home = home_dir().expect("Could not determine home directory");
     let shelly_config = PathBuf::from(&home).join(".shelly").join("shelly.json");
     let json_content = fs::read_to_string(shelly_config).expect("Unable to read file");
-    let parsed = json::parse(&json_content).unwrap();
-    parsed
+    json::parse(&json_content).unwrap()
 }
 
 
@@ -0,0 +1,126 @@
+use crate::config::Endpoints;
+use crate::payload::{self, Encoding, Filter, Template, Vars};
+use std::io::{self, Write};
+
+/// What `shelly gen` renders and how it prints it
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    /// Advertised HTTP port (default: the listener's, as a real run would use)
+    pub http_port: Option<u16>,
+    pub encoding: Encoding,
+    pub filter: Filter,
+    pub token: Option<String>,
+    /// `txt`, `json` or `sh`
+    pub format: String,
+}
+
+/// `shelly gen <shell>`: render every matching template without starting a
+/// listener or asking anything. `txt` prints one command per line (for
+/// wordlists), `json` an array with each template's metadata, `sh` a
+/// script of shell-quoted `PAYLOAD_<n>` variables to source. Warnings go
+/// to stderr. Returns the exit code.
+pub fn run(config: &json::JsonValue, shell: &str, options: &Options) -> i32 {
+    let shell_obj = &config["shells"][shell];
+    if shell_obj.is_null() {
+        eprintln!("⚠️  shell '{}' not found in config", shell);
+        return 1;
+    }
+
+    let mut endpoints = Endpoints::from_config(config, &options.host, options.port);
+    endpoints.advertise_host = options.host.clone();
+    endpoints.advertise_port = options.port;
+    // the multiplexer answers HTTP on the shell port itself
+    if shell_obj["listener"].as_str() == Some("mux") {
+        endpoints.advertise_http_port = options.port;
+    }
+    if let Some(http_port) = options.http_port {
+        endpoints.advertise_http_port = http_port;
+    }
+    let vars = Vars::new(shell, shell_obj, &endpoints, options.token.as_deref());
+
+    let templates = payload::templates(shell_obj);
+    let mut rendered = Vec::new();
+    for (i, template) in templates.iter().enumerate() {
+        if !options.filter.matches(template) {
+            continue;
+        }
+        let output = payload::render(&template.command, &vars);
+        for warning in &output.warnings {
+            eprintln!("⚠️  [{}] {}", i + 1, warning);
+        }
+        rendered.push((i + 1, template, payload::encode(&output.command, options.encoding), output.warnings));
+    }
+
+    // `| head` closing the pipe early is a normal way to stop
+    match print(&mut io::stdout().lock(), shell, &endpoints, &rendered, options) {
+        Ok(()) => 0,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("⚠️  {}", e);
+            1
+        }
+    }
+}
+
+/// (template number, template, command, warnings)
+type Rendered<'a> = (usize, &'a Template, String, Vec<String>);
+
+fn print(
+    out: &mut impl Write,
+    shell: &str,
+    endpoints: &Endpoints,
+    rendered: &[Rendered],
+    options: &Options,
+) -> io::Result<()> {
+    match options.format.as_str() {
+        "json" => {
+            let mut list = json::JsonValue::new_array();
+            for (number, template, command, warnings) in rendered {
+                let mut entry = json::object! {
+                    "shell": shell,
+                    "template": *number,
+                    "command": command.as_str(),
+                    "encoding": options.encoding.name(),
+                    "os": template.os.clone(),
+                    "requires": template.requires.clone(),
+                    "pty": template.pty,
+                    "transport": template.transport.clone(),
+                    "description": template.description.clone(),
+                    "tags": template.tags.clone(),
+                    "warnings": warnings.clone(),
+                };
+                if let Some(token) = &options.token {
+                    entry["token"] = token.as_str().into();
+                }
+                let _ = list.push(entry);
+            }
+            writeln!(out, "{}", json::stringify_pretty(list, 2))?;
+        }
+        "sh" => {
+            writeln!(out, "#!/bin/sh")?;
+            writeln!(
+                out,
+                "# shelly payloads for {} calling back to {}",
+                shell,
+                crate::helpers::host_port(&endpoints.advertise_host, endpoints.advertise_port)
+            )?;
+            for (n, (number, template, command, _)) in rendered.iter().enumerate() {
+                let summary = template.summary();
+                if summary.is_empty() {
+                    writeln!(out, "\n# {}#{}", shell, number)?;
+                } else {
+                    writeln!(out, "\n# {}#{}: {}", shell, number, summary)?;
+                }
+                writeln!(out, "PAYLOAD_{}='{}'", n + 1, command.replace('\'', "'\\''"))?;
+            }
+            writeln!(out, "\nPAYLOAD_COUNT={}", rendered.len())?;
+        }
+        _ => {
+            for (_, _, command, _) in rendered {
+                writeln!(out, "{}", command)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
-use std::io;
+use std::io::{self, Write};
+use std::net::{TcpListener, UdpSocket};
 use std::process::Command;
 use pnet::datalink::{self};
 use terminal_menu::{menu, label, button, run, mut_menu};
@@ -9,6 +10,25 @@ pub fn cls() {
         .expect("Failed to clear the screen");
 }
 
+/// Format a unix timestamp as ISO-8601 UTC, e.g. 2025-10-18T23:12:13Z
+pub fn utc_timestamp(secs: u64) -> String {
+    // days-to-civil conversion from Howard Hinnant's date algorithms
+    let days = (secs / 86400) as i64 + 719468;
+    let rem = secs % 86400;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
 pub fn port_input() -> Result<u16, String> {
     cls();
     let mut input = String::new();
@@ -23,35 +43,294 @@ pub fn port_input() -> Result<u16, String> {
     }
     
     match trimmed_input.parse::<u16>() {
-        Ok(port) if port > 0 && port <= 65535 => Ok(port),
+        Ok(port) if port > 0 => Ok(port),
         Ok(_) => Err(format!("Port number {} is out of range (0-65535)", trimmed_input)),
         Err(_) => Err(format!("Invalid input: '{}' is not a valid number", trimmed_input)),
     }
 }
 
-pub fn interface_selector() -> String {
-    let interfaces = datalink::interfaces();
-    let mut menu_items = vec![label("Select Listening Interface")];
-    for interface in interfaces {
-        if interface.is_loopback() || interface.ips.is_empty() {
+/// A listening address and the interface it belongs to
+pub struct InterfaceAddr {
+    pub name: String,
+    pub ip: String,
+}
+
+/// Default `shelly.preferred_interfaces`: VPN tunnels first
+const PREFERRED_INTERFACES: [&str; 2] = ["tun*", "wg*"];
+
+/// Non-loopback addresses of every interface, ordered by the last chosen
+/// interface, then `shelly.preferred_interfaces` (`*` matches any suffix),
+/// then IPv4 before IPv6
+pub fn interface_addresses(config: &json::JsonValue) -> Vec<InterfaceAddr> {
+    let mut patterns: Vec<String> = config["shelly"]["preferred_interfaces"]
+        .members()
+        .filter_map(|p| p.as_str().map(|s| s.to_string()))
+        .collect();
+    if !config["shelly"]["preferred_interfaces"].is_array() {
+        patterns = PREFERRED_INTERFACES.iter().map(|p| p.to_string()).collect();
+    }
+    if let Some(last) = crate::config::last_interface() {
+        patterns.insert(0, last);
+    }
+
+    let mut addrs = Vec::new();
+    for interface in datalink::interfaces() {
+        if interface.is_loopback() {
             continue;
         }
-        for ip in interface.ips {
-            if ip.is_ipv4() {
-                if let pnet::ipnetwork::IpNetwork::V4(addr) = ip {
-                    menu_items.push(button(&addr.ip().to_string()));
+        for ip in &interface.ips {
+            match ip {
+                pnet::ipnetwork::IpNetwork::V4(addr) => addrs.push(InterfaceAddr {
+                    name: interface.name.clone(),
+                    ip: addr.ip().to_string(),
+                }),
+                // link-local addresses need a scope id the target can't use
+                pnet::ipnetwork::IpNetwork::V6(addr) if !addr.ip().is_unicast_link_local() => {
+                    addrs.push(InterfaceAddr {
+                        name: interface.name.clone(),
+                        ip: addr.ip().to_string(),
+                    })
                 }
+                pnet::ipnetwork::IpNetwork::V6(_) => {}
             }
         }
     }
 
+    addrs.sort_by_key(|a| (preference_rank(&a.name, &patterns), is_ipv6(&a.ip)));
+    addrs
+}
+
+/// Position of the first pattern matching `name`, or last place
+fn preference_rank(name: &str, patterns: &[String]) -> usize {
+    patterns
+        .iter()
+        .position(|p| match p.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == p,
+        })
+        .unwrap_or(patterns.len())
+}
+
+/// Address of the named interface (`--iface tun0`), IPv4 preferred
+pub fn interface_ip(config: &json::JsonValue, name: &str) -> Result<String, String> {
+    let addrs = interface_addresses(config);
+    match addrs.iter().find(|a| a.name == name) {
+        Some(addr) => {
+            crate::config::remember_interface(name);
+            Ok(addr.ip.clone())
+        }
+        None => {
+            let mut names: Vec<&str> = addrs.iter().map(|a| a.name.as_str()).collect();
+            names.sort();
+            names.dedup();
+            Err(format!(
+                "Interface '{}' not found or has no address (available: {})",
+                name,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Pick the listening address. With `shelly.remember_interface` set, the
+/// last chosen interface is reused without showing the menu. Returns None
+/// if the menu is canceled.
+pub fn interface_selector(config: &json::JsonValue) -> Option<String> {
+    if config["shelly"]["remember_interface"].as_bool().unwrap_or(false)
+        && let Some(last) = crate::config::last_interface()
+        && let Ok(ip) = interface_ip(config, &last)
+    {
+        println!("ℹ️  Using {} ({}), pass --iface to pick another", last, ip);
+        return Some(ip);
+    }
+
+    if crate::config::prompts().non_interactive {
+        eprintln!("⚠️  No listening interface; pass --iface, -l or set SHELLY_IFACE");
+        return None;
+    }
+
+    let addrs = interface_addresses(config);
+    if addrs.is_empty() {
+        eprintln!("⚠️  No interface with a usable address, pass -l <ip>");
+        return None;
+    }
+    let mut menu_items = vec![label("Select Listening Interface")];
+    for addr in &addrs {
+        menu_items.push(button(format!("{:<10} {}", addr.name, addr.ip)));
+    }
+
     let menu = menu(menu_items);
     run(&menu);
-    
+
     if mut_menu(&menu).canceled() {
         println!("Canceled!");
-        std::process::exit(0);
+        return None;
     }
-    
-    return mut_menu(&menu).selected_item_name().to_string();
-}
\ No newline at end of file
+
+    // index 0 is the label
+    let chosen = &addrs[mut_menu(&menu).selected_item_index() - 1];
+    crate::config::remember_interface(&chosen.name);
+    Some(chosen.ip.clone())
+}
+
+/// Bind a TCP listener. `::` is dual-stack on Linux so it takes IPv4 and
+/// IPv6 alike; hosts without IPv6 fall back to `0.0.0.0`.
+pub fn bind_tcp(host: &str, port: u16) -> io::Result<TcpListener> {
+    match TcpListener::bind((host, port)) {
+        Err(_) if host == "::" => TcpListener::bind(("0.0.0.0", port)),
+        result => result,
+    }
+}
+
+/// UDP counterpart of `bind_tcp`
+pub fn bind_udp(host: &str, port: u16) -> io::Result<UdpSocket> {
+    match UdpSocket::bind((host, port)) {
+        Err(_) if host == "::" => UdpSocket::bind(("0.0.0.0", port)),
+        result => result,
+    }
+}
+
+/// A port the kernel considers free on loopback right now
+pub fn loopback_port(udp: bool) -> io::Result<u16> {
+    if udp {
+        Ok(bind_udp("127.0.0.1", 0)?.local_addr()?.port())
+    } else {
+        Ok(bind_tcp("127.0.0.1", 0)?.local_addr()?.port())
+    }
+}
+
+/// Put `text` on the clipboard of the terminal emulator with an OSC 52
+/// escape, which also works over SSH. Inside tmux the sequence is wrapped
+/// in a passthrough (needs `set -g allow-passthrough on` or `set-clipboard on`).
+pub fn osc52_copy(text: &str) -> io::Result<()> {
+    let sequence = format!("\x1b]52;c;{}\x07", crate::payload::base64(text.as_bytes()));
+    let sequence = if std::env::var_os("TMUX").is_some() {
+        format!("\x1bPtmux;\x1b{}\x1b\\", sequence)
+    } else {
+        sequence
+    };
+    let mut stdout = io::stdout();
+    stdout.write_all(sequence.as_bytes())?;
+    stdout.flush()
+}
+
+/// Test-bind `port` before any payload points at it, explaining the usual
+/// failures and suggesting a nearby free port. `exact` binds `host` as is,
+/// the way nc and socat do; otherwise it goes through `bind_tcp`/`bind_udp`
+/// like shelly's own listeners.
+pub fn preflight(host: &str, port: u16, udp: bool, exact: bool) -> Result<(), String> {
+    let Err(e) = try_bind(host, port, udp, exact) else {
+        return Ok(());
+    };
+
+    let suggestion = match free_port_near(host, port, udp, exact) {
+        Some(free) => format!("; port {} is free", free),
+        None => String::new(),
+    };
+    Err(format!(
+        "Cannot listen on {} ({}): {}{}",
+        host_port(host, port),
+        if udp { "udp" } else { "tcp" },
+        bind_failure(&e),
+        suggestion
+    ))
+}
+
+/// Why a bind failed, in terms of what to do about it
+fn bind_failure(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => "already in use (another listener, or shelly running elsewhere?)".to_string(),
+        io::ErrorKind::PermissionDenied => {
+            "ports below 1024 need root or cap_net_bind_service".to_string()
+        }
+        io::ErrorKind::AddrNotAvailable => {
+            "not an address of this machine (use --bind/--advertise behind a forward)".to_string()
+        }
+        _ => e.to_string(),
+    }
+}
+
+fn try_bind(host: &str, port: u16, udp: bool, exact: bool) -> io::Result<()> {
+    match (udp, exact) {
+        (true, true) => UdpSocket::bind((host, port)).map(|_| ()),
+        (true, false) => bind_udp(host, port).map(|_| ()),
+        (false, true) => TcpListener::bind((host, port)).map(|_| ()),
+        (false, false) => bind_tcp(host, port).map(|_| ()),
+    }
+}
+
+/// First bindable unprivileged port in the hundred above `port`
+fn free_port_near(host: &str, port: u16, udp: bool, exact: bool) -> Option<u16> {
+    let start = port.max(1023).saturating_add(1);
+    (start..=start.saturating_add(99)).find(|&p| try_bind(host, p, udp, exact).is_ok())
+}
+
+/// `host:port`, bracketing IPv6 hosts
+pub fn host_port(host: &str, port: u16) -> String {
+    if is_ipv6(host) {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Whether a host string is an IPv6 address
+pub fn is_ipv6(host: &str) -> bool {
+    matches!(host.parse::<std::net::IpAddr>(), Ok(std::net::IpAddr::V6(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_timestamp_fixed_epochs() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(1234567890), "2009-02-13T23:31:30Z");
+        assert_eq!(utc_timestamp(1735689599), "2024-12-31T23:59:59Z");
+        assert_eq!(utc_timestamp(1735689600), "2025-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(253402300799), "9999-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn utc_timestamp_leap_years() {
+        // 2000 is a leap year (divisible by 400), 2100 isn't
+        assert_eq!(utc_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(951868800), "2000-03-01T00:00:00Z");
+        assert_eq!(utc_timestamp(1709164800), "2024-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(4107456000), "2100-02-28T00:00:00Z");
+        assert_eq!(utc_timestamp(4107542400), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn preflight_port_in_use() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let e = preflight("127.0.0.1", port, false, true).unwrap_err();
+        let expected = format!("Cannot listen on 127.0.0.1:{} (tcp): already in use", port);
+        assert!(e.starts_with(&expected), "{}", e);
+        assert!(e.contains("; port ") && e.ends_with(" is free"), "{}", e);
+        drop(taken);
+        assert_eq!(preflight("127.0.0.1", port, false, true), Ok(()));
+    }
+
+    #[test]
+    fn bind_failure_reasons() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(bind_failure(&denied), "ports below 1024 need root or cap_net_bind_service");
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+        assert!(bind_failure(&in_use).starts_with("already in use"));
+        let elsewhere = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        assert!(bind_failure(&elsewhere).starts_with("not an address of this machine"));
+    }
+
+    #[test]
+    fn free_port_near_skips_taken_and_privileged_ports() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port() - 1;
+        let free = free_port_near("127.0.0.1", port, false, true).unwrap();
+        assert!(free > port + 1 && free <= port + 100, "{} near {}", free, port);
+        assert!(TcpListener::bind(("127.0.0.1", free)).is_ok());
+        assert!(free_port_near("127.0.0.1", 80, false, true).unwrap() > 1023);
+    }
+}
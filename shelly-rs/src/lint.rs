@@ -0,0 +1,271 @@
+use crate::payload::{self, Encoding, Template};
+
+/// Listener types `start_listener` knows how to run
+pub const LISTENERS: [&str; 5] = ["netcat", "mux", "udp", "socat_raw", "connect"];
+
+/// Something in shelly.json that will only fail once it's used
+pub struct Problem {
+    /// Shell it belongs to, None for toolbox entries
+    pub shell: Option<String>,
+    /// Where in the config, e.g. `shells.nc.templates[2]`
+    pub path: String,
+    pub message: String,
+}
+
+/// Lint every shell (listener, `encode`, `vars`, templates and their
+/// respawn variants, `serve` entries) and the toolbox
+pub fn check(config: &json::JsonValue) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for (name, shell_obj) in config["shells"].entries() {
+        check_shell(name, shell_obj, &config["toolbox"], &mut problems);
+    }
+    for (name, entry) in config["toolbox"].entries() {
+        check_toolbox(name, entry, &mut problems);
+    }
+    problems
+}
+
+fn check_shell(name: &str, shell_obj: &json::JsonValue, toolbox: &json::JsonValue, problems: &mut Vec<Problem>) {
+    let mut report = |path: String, message: String| {
+        problems.push(Problem {
+            shell: Some(name.to_string()),
+            path: format!("shells.{}{}", name, path),
+            message,
+        })
+    };
+
+    if !shell_obj["listener"].is_null() {
+        match shell_obj["listener"].as_str() {
+            Some(listener) if LISTENERS.contains(&listener) => {}
+            _ => report(
+                ".listener".to_string(),
+                format!("unknown listener {} (expected one of {})", shell_obj["listener"], LISTENERS.join(", ")),
+            ),
+        }
+    }
+
+    if let Some(encoding) = shell_obj["encode"].as_str()
+        && let Err(e) = Encoding::parse(encoding)
+    {
+        report(".encode".to_string(), e);
+    }
+
+    let vars: Vec<String> = shell_obj["vars"].entries().map(|(k, _)| k.to_string()).collect();
+    for (var, value) in shell_obj["vars"].entries() {
+        match value.as_str() {
+            Some(value) => {
+                for message in payload::lint(value, &[], false) {
+                    report(format!(".vars.{}", var), message);
+                }
+            }
+            None => report(format!(".vars.{}", var), "not a string".to_string()),
+        }
+    }
+
+    // connect shells dial out and may not print anything to run
+    if !shell_obj["templates"].is_array() {
+        if shell_obj["listener"].as_str() != Some("connect") {
+            report(".templates".to_string(), "missing or not an array".to_string());
+        }
+    } else {
+        for (i, value) in shell_obj["templates"].members().enumerate() {
+            let path = format!(".templates[{}]", i);
+            let Some(template) = Template::from_json(value) else {
+                report(path, "neither a command string nor an object with a \"command\"".to_string());
+                continue;
+            };
+            let windows = template.os.as_deref() == Some("windows");
+            for message in payload::lint(&template.command, &vars, windows) {
+                report(path.clone(), message);
+            }
+            if let Some(respawn) = &template.respawn {
+                for message in payload::lint(respawn, &vars, windows) {
+                    report(format!("{}.respawn", path), message);
+                }
+            }
+        }
+    }
+
+    for (i, entry) in shell_obj["serve"].members().enumerate() {
+        match entry.as_str() {
+            Some(file) if toolbox.has_key(file) => {}
+            Some(file) => report(format!(".serve[{}]", i), format!("'{}' is not in the toolbox", file)),
+            None => report(format!(".serve[{}]", i), "not a string".to_string()),
+        }
+    }
+}
+
+fn check_toolbox(name: &str, entry: &json::JsonValue, problems: &mut Vec<Problem>) {
+    let mut report = |path: String, message: &str| {
+        problems.push(Problem {
+            shell: None,
+            path: format!("toolbox.{}{}", name, path),
+            message: message.to_string(),
+        })
+    };
+
+    if !entry.is_object() {
+        report(String::new(), "not an object of architectures");
+        return;
+    }
+    for (arch, file) in entry.entries() {
+        let path = format!(".{}", arch);
+        if file["filename"].as_str().is_none_or(str::is_empty) {
+            report(path.clone(), "no \"filename\"");
+        }
+        if let Some(url) = file["download"].as_str() {
+            let scheme = url.starts_with("http://") || url.starts_with("https://");
+            if !scheme || url.contains(char::is_whitespace) {
+                report(format!("{}.download", path), "not a valid http(s) url");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    /// `path: message` for every problem in a config
+    fn problems(config: &str) -> Vec<String> {
+        let config = json::parse(config).unwrap();
+        check(&config).into_iter().map(|p| format!("{}: {}", p.path, p.message)).collect()
+    }
+
+    /// Problems of a single shell `s` with an empty toolbox
+    fn shell(body: &str) -> Vec<String> {
+        problems(&format!(r#"{{"shells": {{"s": {}}}, "toolbox": {{}}}}"#, body))
+    }
+
+    #[test]
+    fn clean_shell() {
+        assert!(shell(r#"{"vars": {"cmd": "id"}, "templates": ["nc {ip} {port} -e {{cmd|base64}}", "echo ${HOME}"]}"#).is_empty());
+    }
+
+    #[test]
+    fn unknown_listener() {
+        let found = shell(r#"{"listener": "ncat", "templates": []}"#);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("shells.s.listener: unknown listener ncat "), "{}", found[0]);
+        assert!(shell(r#"{"listener": "mux", "templates": []}"#).is_empty());
+    }
+
+    #[test]
+    fn bad_encode() {
+        let found = shell(r#"{"encode": "rot13", "templates": []}"#);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("shells.s.encode: "), "{}", found[0]);
+        assert!(shell(r#"{"encode": "double-url", "templates": []}"#).is_empty());
+    }
+
+    #[test]
+    fn var_lint() {
+        assert_eq!(
+            shell(r#"{"vars": {"a": "{{ip|rot13}}"}, "templates": []}"#),
+            ["shells.s.vars.a: unknown template filter 'rot13'"]
+        );
+        // vars can't use other vars
+        assert_eq!(
+            shell(r#"{"vars": {"a": "id", "b": "{{a}}"}, "templates": []}"#),
+            ["shells.s.vars.b: unknown template variable 'a'"]
+        );
+    }
+
+    #[test]
+    fn non_string_var() {
+        assert_eq!(shell(r#"{"vars": {"n": 5}, "templates": []}"#), ["shells.s.vars.n: not a string"]);
+    }
+
+    #[test]
+    fn missing_templates() {
+        assert_eq!(shell("{}"), ["shells.s.templates: missing or not an array"]);
+        assert_eq!(shell(r#"{"templates": "nc {ip} {port}"}"#), ["shells.s.templates: missing or not an array"]);
+        // connect shells don't need any
+        assert!(shell(r#"{"listener": "connect"}"#).is_empty());
+    }
+
+    #[test]
+    fn bad_template() {
+        assert_eq!(
+            shell(r#"{"templates": [5, {"os": "linux"}]}"#),
+            [
+                "shells.s.templates[0]: neither a command string nor an object with a \"command\"",
+                "shells.s.templates[1]: neither a command string nor an object with a \"command\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        assert_eq!(
+            shell(r#"{"templates": ["nc {host} {port}", "awk 'BEGIN {print 1}'"]}"#),
+            ["shells.s.templates[0]: unknown placeholder '{host}'"]
+        );
+        assert_eq!(
+            shell(r#"{"templates": ["nc {{host}} {port}"]}"#),
+            ["shells.s.templates[0]: unknown template variable 'host'"]
+        );
+    }
+
+    #[test]
+    fn vars_in_single_braces() {
+        assert_eq!(
+            shell(r#"{"vars": {"cmd": "id"}, "templates": ["echo {cmd} | {shell}"]}"#),
+            [
+                "shells.s.templates[0]: '{cmd}' is only filled in as '{{cmd}}'",
+                "shells.s.templates[0]: '{shell}' is only filled in as '{{shell}}'",
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_quotes() {
+        assert_eq!(shell(r#"{"templates": ["bash -c 'id"]}"#), ["shells.s.templates[0]: unbalanced single quote"]);
+        assert_eq!(shell(r#"{"templates": ["echo \"it's"]}"#), ["shells.s.templates[0]: unbalanced double quote"]);
+        // cmd doesn't escape with backslashes
+        assert!(shell(r#"{"templates": [{"command": "cmd /c \"type C:\\temp\\\"", "os": "windows"}]}"#).is_empty());
+        assert_eq!(
+            shell(r#"{"templates": [{"command": "cmd /c \"type C:\\temp\\\""}]}"#),
+            ["shells.s.templates[0]: unbalanced double quote"]
+        );
+    }
+
+    #[test]
+    fn respawn() {
+        assert_eq!(
+            shell(r#"{"templates": [{"command": "nc {ip} {port}", "respawn": "while :; do nc {ip} {prot}; done"}]}"#),
+            ["shells.s.templates[0].respawn: unknown placeholder '{prot}'"]
+        );
+    }
+
+    #[test]
+    fn serve_not_in_toolbox() {
+        assert_eq!(
+            problems(r#"{"shells": {"s": {"templates": [], "serve": ["socat", "linpeas", 3]}}, "toolbox": {"socat": {}}}"#),
+            [
+                "shells.s.serve[1]: 'linpeas' is not in the toolbox",
+                "shells.s.serve[2]: not a string",
+            ]
+        );
+    }
+
+    #[test]
+    fn toolbox_filename_and_url() {
+        assert_eq!(
+            problems(
+                r#"{"shells": {}, "toolbox": {
+                    "socat": {"lin_64": {"filename": "socatx64.bin", "download": "https://example.com/socat"},
+                              "lin_32": {"download": "ftp://example.com/socat"},
+                              "arm": {"filename": "socat", "download": "https://example.com/so cat"}},
+                    "linpeas": "linpeas.sh"
+                }}"#
+            ),
+            [
+                "toolbox.socat.lin_32: no \"filename\"",
+                "toolbox.socat.lin_32.download: not a valid http(s) url",
+                "toolbox.socat.arm.download: not a valid http(s) url",
+                "toolbox.linpeas: not an object of architectures",
+            ]
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::builder::BoolishValueParser;
+use clap::{Parser, Subcommand};
 use std::io::{self, Write};
 use std::sync::mpsc;
 use std::thread;
@@ -7,126 +8,263 @@ mod config;
 mod shell;
 mod serve;
 mod session;
+mod mux;
+mod token;
+mod scope;
+mod payload;
+mod picker;
+mod lint;
+mod selftest;
+mod catalog;
+mod export;
 
 /// Simple Nc wrapper with revshell generation and session management
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
-    /// Listen Interface. Omit for interactive prompt
+    /// Host payloads call back to; listeners bind --bind (default: all interfaces). Omit for interactive prompt
     l_host: Option<String>,
 
+    /// Call back to this interface's address (e.g. tun0) instead of prompting
+    #[arg(short, long, env = "SHELLY_IFACE")]
+    iface: Option<String>,
+
     /// Listening Port
-    #[arg(short, long)]
+    #[arg(short, long, env = "SHELLY_PORT")]
     port: Option<u16>,
 
-    /// Reverse shell type to list
-    #[arg(default_value = "bash")]
-    shell: String,
+    /// Reverse shell type to list. Omit for an interactive picker
+    shell: Option<String>,
 
     /// Downloads reverse shell executables
-    #[clap(long, short, action)]
+    #[clap(long, short, action, global = true, env = "SHELLY_DOWNLOAD", conflicts_with = "no_download", value_parser = BoolishValueParser::new())]
     download: bool,
+
+    /// Never download missing toolbox files
+    #[clap(long, action, global = true, env = "SHELLY_NO_DOWNLOAD", value_parser = BoolishValueParser::new())]
+    no_download: bool,
+
+    /// Answer yes to every startup question
+    #[clap(long, short, action, global = true, env = "SHELLY_YES", value_parser = BoolishValueParser::new())]
+    yes: bool,
+
+    /// Fail instead of prompting when a startup question has no answer
+    #[clap(long, action, global = true, env = "SHELLY_NON_INTERACTIVE", value_parser = BoolishValueParser::new())]
+    non_interactive: bool,
+
+    /// Encoding for printed payloads: raw, base64, url, double-url, powershell, json
+    #[arg(long, short)]
+    encode: Option<String>,
+
+    /// Serve rendered payload scripts at /s/<shell> on the HTTP server
+    #[arg(long)]
+    stager: bool,
+
+    /// Only list templates for this target OS
+    #[arg(long)]
+    os: Option<String>,
+
+    /// Only list templates that need no binaries beyond these (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    requires: Vec<String>,
+
+    /// Only list templates with this tag
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// Note recorded with the printed payloads (target, exploit, ...)
+    #[arg(long)]
+    note: Option<String>,
+
+    /// Print respawning variants that call back again when the shell dies
+    #[arg(long)]
+    respawn: bool,
+
+    /// Token payloads must send as their first line ("auto" for a random one)
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Local address listeners bind to (default: all interfaces)
+    #[arg(long)]
+    bind: Option<String>,
+
+    /// Host payloads call back to when it isn't a local interface (port forward, redirector, tunnel)
+    #[arg(long)]
+    advertise: Option<String>,
+
+    /// Port payloads call back to, if it differs from the listening port
+    #[arg(long)]
+    advertise_port: Option<u16>,
+
+    /// HTTP port payloads download from, if it differs from default_http_svr
+    #[arg(long)]
+    advertise_http_port: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-fn main() {
-    let _ = config::ensure_exists();
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Connect to a bind shell listening on the target
+    Connect {
+        /// Target host
+        host: String,
+        /// Target port
+        port: u16,
+    },
+    /// Run each template of a shell locally against a loopback listener
+    Selftest {
+        /// Shell from shelly.json to test
+        shell: String,
+    },
+    /// Print a shell's rendered payloads for other tools, without listening
+    Gen {
+        /// Shell from shelly.json
+        shell: String,
+        /// Address payloads call back to
+        #[arg(short = 'l', long)]
+        host: String,
+        /// Port payloads call back to
+        #[arg(short, long)]
+        port: u16,
+        /// HTTP port payloads download from (default: the shell's listener's)
+        #[arg(long)]
+        http_port: Option<u16>,
+        /// Output encoding: raw, base64, url, double-url, powershell, json
+        #[arg(short, long)]
+        encode: Option<String>,
+        /// txt (one per line), json (with metadata) or sh (PAYLOAD_<n> variables)
+        #[arg(short, long, default_value = "txt", value_parser = ["txt", "json", "sh"])]
+        format: String,
+        /// Token payloads send first (default: the shell's, unless that is "auto")
+        #[arg(long)]
+        token: Option<String>,
+        /// Only templates for this os
+        #[arg(long)]
+        os: Option<String>,
+        /// Only templates that need no binaries beyond these (comma separated)
+        #[arg(long, value_delimiter = ',')]
+        requires: Vec<String>,
+        /// Only templates with this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
+    /// Merge a revshells-style JSON catalog into the shells in shelly.json
+    Import {
+        /// Catalog file
+        file: std::path::PathBuf,
+        /// Shell to add templates to (default: the file's name; bind shells go to <into>-bind)
+        #[arg(long)]
+        into: Option<String>,
+        /// What the catalog's {shell} becomes in non-windows commands
+        #[arg(long, default_value = "sh")]
+        target_shell: String,
+        /// Report what would be imported without saving
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Work with shelly.json
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
 
-    let args = Args::parse();
-    let ip: String;
-    let port: u16;
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Lint shells, templates and toolbox entries
+    Check,
+}
 
-    if let Some(host) = &args.l_host {
-        ip = host.clone();
-    } else {
-        ip = helpers::interface_selector();
-    }
+fn main() {
+    let args = Args::parse();
 
-    if let Some(p) = args.port {
-        port = p;
-    } else {
-        port = helpers::port_input().unwrap_or(4444);
+    config::set_prompts(config::Prompts {
+        assume_yes: args.yes,
+        download: args.download,
+        no_download: args.no_download,
+        // gen output goes to other tools, so it never asks anything
+        non_interactive: args.non_interactive || matches!(args.command, Some(Commands::Gen { .. })),
+    });
+    if let Err(e) = config::ensure_exists() {
+        eprintln!("⚠️  {}", e);
+        std::process::exit(1);
     }
 
-    helpers::cls();
-    println!("ℹ️  {2} Revshells for {0}:{1}\n", ip, port, args.shell);
-
     // Load config once and clone it for use in threads
     let config = config::load_config();
-    let config_clone = config.clone();
-
-    let mut listener_type = String::new();
-    let http_port = config_clone["shelly"]["default_http_svr"].as_u16().unwrap_or(8000);
-
-    if config_clone.has_key("shells") {
-        let shells = &config_clone["shells"];
-        if shells.has_key(&args.shell) {
-            let shell_obj = &shells[&args.shell];
-            if shell_obj.has_key("listener"){
-                listener_type = shell_obj["listener"].as_str().unwrap_or("netcat").to_string();
-            }
-            if shell_obj.has_key("serve"){
-                let serve_files_vec: Vec<String> = shell_obj["serve"]
-                    .members()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect();
-                serve::build_from_config(&serve_files_vec, &config_clone, http_port);
-            }
-            if shell_obj.has_key("templates") && shell_obj["templates"].is_array() {
-                for t in shell_obj["templates"].members() {
-                    if let Some(s) = t.as_str() {
-                        println!("{}", s.replace("{ip}", &ip).replace("{port}", &port.to_string()).replace("{http_port}", &http_port.to_string()));
-                    }
-                }
-            } else {
-                eprintln!("templates is missing or not an array for shell '{}'", args.shell);
-            }
-        } else {
-            eprintln!("shell '{}' not found in config", args.shell);
+    match &args.command {
+        Some(Commands::Config { action: ConfigAction::Check }) => std::process::exit(config_check(&config)),
+        Some(Commands::Import { file, into, target_shell, dry_run }) => {
+            let options = catalog::Options {
+                into: into.clone(),
+                target_shell: target_shell.clone(),
+                dry_run: *dry_run,
+            };
+            std::process::exit(catalog::run(&mut config.clone(), file, &options));
         }
-    }
-            if shell_obj.has_key("serve"){
-                let serve_files_vec: Vec<String> = shell_obj["serve"]
-                    .members()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect();
-                serve::build_from_config(&serve_files_vec, &config_clone, http_port);
-            }
-            if shell_obj.has_key("templates") && shell_obj["templates"].is_array() {
-                for t in shell_obj["templates"].members() {
-                    if let Some(s) = t.as_str() {
-                        println!("{}", s.replace("{ip}", &ip).replace("{port}", &port.to_string()).replace("{http_port}", &http_port.to_string()));
-                    }
+        Some(Commands::Gen { shell, host, port, http_port, encode, format, token, os, requires, tag }) => {
+            let shell_obj = &config["shells"][shell.as_str()];
+            let encoding = match resolve_encoding(encode.as_deref(), shell_obj) {
+                Ok(encoding) => encoding,
+                Err(e) => {
+                    eprintln!("⚠️  {}", e);
+                    std::process::exit(1);
                 }
-            } else {
-                eprintln!("templates is missing or not an array for shell '{}'", shell);
+            };
+            // a per-run token only exists once a listener has picked it
+            if token.as_deref().or(shell_obj["token"].as_str()) == Some("auto") {
+                eprintln!("⚠️  {} uses a random per-run token; pass --token with the one the listener printed", shell);
+                std::process::exit(1);
             }
-        } else {
-            eprintln!("shell '{}' not found in config", shell);
+            let options = export::Options {
+                host: host.clone(),
+                port: *port,
+                http_port: *http_port,
+                encoding,
+                filter: payload::Filter {
+                    os: os.clone(),
+                    requires: requires.clone(),
+                    tag: tag.clone(),
+                },
+                token: token::resolve(token.as_deref().or(shell_obj["token"].as_str())),
+                format: format.clone(),
+            };
+            std::process::exit(export::run(&config, shell, &options));
         }
+        _ => {}
+    }
+    scope::init(&config);
+    if let Some(Commands::Selftest { shell }) = &args.command {
+        std::process::exit(selftest::run(&config, shell));
     }
 
-    // Start the listener in a separate thread
     let (tx, rx) = mpsc::channel();
-    let listener_thread = thread::spawn(move || {
-        if listener_type.eq("socat_raw"){
-            println!("\n Running socat in raw mode");
-            let socat = shell::Socat::new(port);
-            if let Err(e) = socat.run_with_callback(|session_id: usize| {
-                let _ = tx.send(("session_created", session_id.to_string()));
-            }) {
-                eprintln!("socat error: {}", e);
-            }
-        } else {
-            println!("\nℹ️  Running nc");
-            let netcat = shell::Netcat::new(port, config_clone, &ip);
-            if let Err(e) = netcat.run_with_callback(|session_id: usize| {
-                let _ = tx.send(("session_created", session_id.to_string()));
-            }) {
-                eprintln!("netcat error: {}", e);
-            }
+    let mut listening = None;
+    let listener_thread = match &args.command {
+        Some(Commands::Connect { host, port }) => {
+            let connect = shell::Connect::new(host, *port, config.clone());
+            thread::spawn(move || {
+                if let Err(e) = connect.run_with_callback(|session_id: usize| {
+                    let _ = tx.send(("session_created", session_id.to_string()));
+                }) {
+                    eprintln!("connect error: {}", e);
+                }
+            })
         }
-    });
+        Some(Commands::Config { .. } | Commands::Gen { .. } | Commands::Import { .. } | Commands::Selftest { .. }) => {
+            unreachable!("handled before listening")
+        }
+        None => match start_listener(&args, &config, tx) {
+            Some((handle, started)) => {
+                listening = Some(started);
+                handle
+            }
+            None => std::process::exit(1),
+        },
+    };
 
     // Main interactive loop
     loop {
@@ -145,6 +283,19 @@ fn main() {
 
         match input {
             "sessions" => list_sessions(),
+            "rejected" => list_rejected(),
+            "history" => list_history(),
+            cmd if cmd.starts_with("note ") => {
+                let parts: Vec<&str> = cmd.splitn(3, ' ').collect();
+                match (parts.get(1).and_then(|id| id.trim_start_matches('p').parse::<usize>().ok()), parts.get(2)) {
+                    (Some(id), Some(note)) => {
+                        if !session::PAYLOAD_HISTORY.set_note(id, note.trim()) {
+                            println!("No payload p{}", id);
+                        }
+                    }
+                    _ => println!("Usage: note <payload id> <text>"),
+                }
+            }
             cmd if cmd.starts_with("switch ") => {
                 let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
                 if parts.len() == 2 {
@@ -157,6 +308,24 @@ fn main() {
                     println!("Usage: switch <session_id>");
                 }
             }
+            cmd if cmd.starts_with("connect ") => {
+                let parts: Vec<&str> = cmd.split_whitespace().collect();
+                if parts.len() == 3 {
+                    if let Ok(port) = parts[2].parse::<u16>() {
+                        connect_session(parts[1], port, &config);
+                    } else {
+                        println!("Invalid port");
+                    }
+                } else {
+                    println!("Usage: connect <host> <port>");
+                }
+            }
+            cmd if cmd.starts_with("scrollback ") => {
+                match cmd["scrollback ".len()..].trim().parse::<usize>() {
+                    Ok(id) => print_scrollback(id),
+                    Err(_) => println!("Usage: scrollback <session_id>"),
+                }
+            }
             cmd if cmd.starts_with("kill ") => {
                 let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
                 if parts.len() == 2 {
@@ -169,6 +338,18 @@ fn main() {
                     println!("Usage: kill <session_id>");
                 }
             }
+            cmd if cmd == "payloads" || cmd.starts_with("payloads ") => match &mut listening {
+                Some(listening) => payloads_command(cmd, &config, listening),
+                None => println!("No listener running to generate payloads for"),
+            },
+            cmd if cmd == "pick" || cmd.starts_with("pick ") => match &listening {
+                Some(listening) => pick_command(cmd, &config, listening),
+                None => println!("No listener running to generate payloads for"),
+            },
+            cmd if cmd.starts_with("copy ") => match &listening {
+                Some(listening) => copy_payload(cmd, &listening.payloads),
+                None => println!("No payloads to copy"),
+            },
             "help" => print_help(),
             "quit" | "exit" => {
                 println!("Goodbye!");
@@ -177,10 +358,10 @@ fn main() {
             _ => {
                 // Check for messages from listener thread
                 while let Ok((msg_type, msg_content)) = rx.try_recv() {
-                    if msg_type == "session_created" {
-                        if let Ok(id) = msg_content.parse::<usize>() {
-                            println!("[*] Session {} created", id);
-                        }
+                    if msg_type == "session_created"
+                        && let Ok(id) = msg_content.parse::<usize>()
+                    {
+                        println!("[*] Session {} created", id);
                     }
                 }
                 
@@ -196,6 +377,267 @@ fn main() {
     let _ = listener_thread.join();
 }
 
+/// What the startup listener tells targets to call back to, kept so the
+/// `payloads` command can re-render templates against it
+struct Listening {
+    shell: String,
+    endpoints: config::Endpoints,
+    /// Whether the HTTP server answers `/s/<shell>`
+    stager: bool,
+    token: Option<String>,
+    /// Commands from the last payload listing, by number
+    payloads: Vec<String>,
+}
+
+/// Print the payloads for the selected shell type and start its listener
+fn start_listener(
+    args: &Args,
+    config: &json::JsonValue,
+    tx: mpsc::Sender<(&'static str, String)>,
+) -> Option<(thread::JoinHandle<()>, Listening)> {
+    let ip: String;
+    let port: u16;
+
+    // the command line says where targets call back to; the config's
+    // advertise_host only fills in when it doesn't, and then there is no
+    // interface to pick
+    let configured_host = config["shelly"]["advertise_host"].as_str();
+    let mut redirected = args.advertise.is_some();
+
+    if let Some(host) = args.advertise.as_ref().or(args.l_host.as_ref()) {
+        ip = host.clone();
+    } else if let Some(name) = &args.iface {
+        match helpers::interface_ip(config, name) {
+            Ok(addr) => ip = addr,
+            Err(e) => {
+                eprintln!("⚠️  {}", e);
+                return None;
+            }
+        }
+    } else if let Some(host) = configured_host {
+        ip = host.to_string();
+        redirected = true;
+    } else {
+        ip = helpers::interface_selector(config)?;
+    }
+
+    if let Some(p) = args.port {
+        port = p;
+    } else if config::prompts().non_interactive {
+        eprintln!("⚠️  No listening port; pass --port or set SHELLY_PORT");
+        return None;
+    } else {
+        port = helpers::port_input().unwrap_or(4444);
+    }
+
+    let shell_name = match &args.shell {
+        Some(shell) => shell.clone(),
+        None if config::prompts().non_interactive => {
+            eprintln!("⚠️  No shell type given; pass one, e.g. `shelly bash`");
+            return None;
+        }
+        None => picker::pick_shell(config)?,
+    };
+
+    let mut endpoints = config::Endpoints::from_config(config, &ip, port);
+    endpoints.advertise_host = ip;
+    if let Some(host) = &args.bind {
+        endpoints.bind_host = host.clone();
+    }
+    if let Some(p) = args.advertise_port {
+        endpoints.advertise_port = p;
+    }
+    if let Some(p) = args.advertise_http_port {
+        endpoints.advertise_http_port = p;
+    }
+
+    helpers::cls();
+    println!(
+        "ℹ️  {1} Revshells for {0}\n",
+        helpers::host_port(&endpoints.advertise_host, endpoints.advertise_port),
+        shell_name
+    );
+    if redirected || endpoints.advertise_port != port {
+        println!(
+            "ℹ️  Listening locally on {}\n",
+            helpers::host_port(&endpoints.bind_host, endpoints.port)
+        );
+    }
+    if let Some(summary) = scope::summary() {
+        println!("ℹ️  {}\n", summary);
+    }
+    report_problems(config, &shell_name);
+
+    let config_clone = config.clone();
+
+    let mut listener_type = String::new();
+    let mut mux_files = Vec::new();
+    let mut token = args.token.clone();
+    let mut payloads = Vec::new();
+    let mut stager = false;
+
+    if config_clone.has_key("shells") {
+        let shells = &config_clone["shells"];
+        if shells.has_key(&shell_name) {
+            let shell_obj = &shells[&shell_name];
+            if shell_obj.has_key("listener"){
+                listener_type = shell_obj["listener"].as_str().unwrap_or("netcat").to_string();
+            }
+            if token.is_none() {
+                token = shell_obj["token"].as_str().map(|s| s.to_string());
+            }
+            token = token::resolve(token.as_deref());
+            // bind shells are dialled, so nothing sends a first line to check
+            if token.is_some() && listener_type.eq("connect") {
+                eprintln!("⚠️  connect shells can't check a token; drop --token or the shell's \"token\"");
+                return None;
+            }
+            stager = args.stager || shell_obj["stager"].as_bool().unwrap_or(shell_obj["stager"].is_number());
+            let http_server = shell_obj.has_key("serve") || stager;
+            // the multiplexer answers HTTP on the shell port itself, unless a
+            // forward for it was given explicitly
+            if listener_type.eq("mux") {
+                endpoints.http_port = endpoints.port;
+                if args.advertise_http_port.is_none() && config["shelly"]["advertise_http_port"].is_null() {
+                    endpoints.advertise_http_port = endpoints.advertise_port;
+                }
+            }
+            // don't hand out payloads for ports that can't be listened on
+            let mut preflight = Ok(());
+            if !listener_type.eq("connect") {
+                let (host, exact) = shell::listen_host(&listener_type, &endpoints, token.as_deref());
+                preflight = helpers::preflight(&host, endpoints.port, listener_type.eq("udp"), exact);
+            }
+            if preflight.is_ok() && http_server && !listener_type.eq("mux") {
+                preflight = helpers::preflight(&endpoints.bind_host, endpoints.http_port, false, false)
+                    .map_err(|e| format!("{} (HTTP server, see default_http_svr)", e));
+            }
+            if let Err(e) = preflight {
+                eprintln!("⚠️  {}", e);
+                return None;
+            }
+            if http_server {
+                let serve_files_vec: Vec<String> = shell_obj["serve"]
+                    .members()
+                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                    .collect();
+                let served = if listener_type.eq("mux") {
+                    serve::collect_from_config(&serve_files_vec, &config_clone).map(|files| {
+                        serve::print_transfer_commands(&files, &endpoints);
+                        mux_files = files
+                    })
+                } else {
+                    let stager = stager.then(|| {
+                        serve::Stager::new(config_clone.clone(), endpoints.clone(), token.clone())
+                    });
+                    serve::build_from_config(
+                        &serve_files_vec,
+                        &config_clone,
+                        &endpoints,
+                        stager,
+                    )
+                };
+                if let Err(e) = served {
+                    eprintln!("⚠️  {}", e);
+                    return None;
+                }
+            }
+            if let Some(ref t) = token {
+                println!(
+                    "ℹ️  Connections must first send a listed payload's own token (or the listener token {})\n",
+                    t
+                );
+            }
+            let listing = Listing {
+                encoding: args.encode.as_deref(),
+                filter: payload::Filter {
+                    os: args.os.clone(),
+                    requires: args.requires.clone(),
+                    tag: args.tag.clone(),
+                },
+                note: args.note.as_deref(),
+                respawn: args.respawn,
+            };
+            match print_payloads(&config_clone, &shell_name, &endpoints, token.as_deref(), &listing) {
+                Ok(printed) => payloads = printed,
+                Err(e) => {
+                    eprintln!("⚠️  {}", e);
+                    return None;
+                }
+            }
+            if stager {
+                let vars = payload::Vars::new(&shell_name, shell_obj, &endpoints, token.as_deref());
+                println!("\nℹ️  Stager: {}", serve::Stager::command(&vars, shell_obj, None));
+            }
+            // a shell chosen from the menu goes straight on to its templates
+            if args.shell.is_none() {
+                let vars = payload::Vars::new(&shell_name, shell_obj, &endpoints, token.as_deref());
+                if let Ok(encoding) = resolve_encoding(args.encode.as_deref(), shell_obj) {
+                    picker::pick_payload(shell_obj, &vars, encoding, stager);
+                }
+            }
+        } else {
+            eprintln!("shell '{}' not found in config", shell_name);
+        }
+    }
+
+    let listening = Listening {
+        shell: shell_name.clone(),
+        endpoints: endpoints.clone(),
+        stager,
+        token: token.clone(),
+        payloads,
+    };
+
+    // Start the listener in a separate thread
+    let handle = thread::spawn(move || {
+        if listener_type.eq("connect") {
+            println!(
+                "\nℹ️  Bind shell payloads: run `connect <target> {}` once one is listening",
+                endpoints.advertise_port
+            );
+        } else if listener_type.eq("mux") {
+            println!("\nℹ️  Running multiplexed listener (shell, tls and http on one port)");
+            match mux::Mux::new(endpoints, config_clone, mux_files, token, stager) {
+                Ok(mux) => {
+                    if let Err(e) = mux.run_with_callback(|session_id: usize| {
+                        let _ = tx.send(("session_created", session_id.to_string()));
+                    }) {
+                        eprintln!("mux error: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("mux error: {}", e),
+            }
+        } else if listener_type.eq("udp") {
+            println!("\nℹ️  Running udp listener");
+            let udp = shell::Udp::new(endpoints, config_clone, token);
+            if let Err(e) = udp.run_with_callback(|session_id: usize| {
+                let _ = tx.send(("session_created", session_id.to_string()));
+            }) {
+                eprintln!("udp error: {}", e);
+            }
+        } else if listener_type.eq("socat_raw"){
+            println!("\n Running socat in raw mode");
+            let socat = shell::Socat::new(endpoints.port, &endpoints.bind_host, token, token::timeout(&config_clone));
+            if let Err(e) = socat.run_with_callback(|session_id: usize| {
+                let _ = tx.send(("session_created", session_id.to_string()));
+            }) {
+                eprintln!("socat error: {}", e);
+            }
+        } else {
+            println!("\nℹ️  Running nc");
+            let netcat = shell::Netcat::new(endpoints, config_clone, token);
+            if let Err(e) = netcat.run_with_callback(|session_id: usize| {
+                let _ = tx.send(("session_created", session_id.to_string()));
+            }) {
+                eprintln!("netcat error: {}", e);
+            }
+        }
+    });
+
+    Some((handle, listening))
+}
+
 fn list_sessions() {
     let sessions = crate::session::SESSION_MANAGER.list_sessions();
     if sessions.is_empty() {
@@ -203,15 +645,79 @@ fn list_sessions() {
         return;
     }
 
-    println!("{:<4} {:<12} {:<20} {:<8}", "Id", "Type", "Target", "Status");
-    println!("{}", "-".repeat(50));
+    println!("{:<4} {:<12} {:<20} {:<8} {:<6} Payload", "Id", "Type", "Target", "Status", "Conns");
+    println!("{}", "-".repeat(77));
     for session in sessions {
         let status = if session.is_active { "Active" } else { "Inactive" };
-        println!("{:<4} {:<12} {:<20} {:<8}", 
+        let payload = session
+            .payload
+            .and_then(|id| session::PAYLOAD_HISTORY.get(id))
+            .map(|issued| issued.label())
+            .unwrap_or_else(|| "-".to_string());
+        println!("{:<4} {:<12} {:<20} {:<8} {:<6} {}", 
                  session.id, 
                  session.session_type, 
-                 format!("{}:{}", session.target_ip, session.listener_port),
-                 status);
+                 helpers::host_port(&session.target_ip, session.listener_port),
+                 status,
+                 session.reconnects + 1,
+                 payload);
+    }
+}
+
+fn print_scrollback(id: usize) {
+    if session::SESSION_MANAGER.get_session(id).is_none() {
+        println!("[!] Session {} not found", id);
+        return;
+    }
+    let output = session::SESSION_MANAGER.scrollback(id);
+    if output.is_empty() {
+        println!("No output kept for session {}", id);
+        return;
+    }
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(&output);
+    if !output.ends_with(b"\n") {
+        println!();
+    }
+}
+
+fn list_history() {
+    let history = session::PAYLOAD_HISTORY.list();
+    if history.is_empty() {
+        println!("No payloads issued");
+        return;
+    }
+
+    println!("{:<5} {:<10} {:<20} {:<18} {:<16} Note", "Id", "Time", "Payload", "Token", "Fetched by");
+    println!("{}", "-".repeat(90));
+    for entry in history {
+        let secs = entry.time % 86400;
+        println!("{:<5} {:<10} {:<20} {:<18} {:<16} {}",
+                 format!("p{}", entry.id),
+                 format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+                 format!("{}#{}", entry.shell, entry.template),
+                 entry.token.as_deref().unwrap_or("-"),
+                 entry.fetched_by.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string()),
+                 entry.note.as_deref().unwrap_or(""));
+    }
+}
+
+fn list_rejected() {
+    let rejected = crate::session::REJECTED_LOG.list();
+    if rejected.is_empty() {
+        println!("No rejected connections");
+        return;
+    }
+
+    println!("{:<10} {:<22} {:<6} Reason", "Time", "Peer", "Port");
+    println!("{}", "-".repeat(70));
+    for entry in rejected {
+        let secs = entry.time % 86400;
+        println!("{:<10} {:<22} {:<6} {}",
+                 format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+                 entry.peer,
+                 entry.listener_port,
+                 entry.reason);
     }
 }
 
@@ -228,6 +734,15 @@ fn switch_session(id: usize) {
     }
 }
 
+fn connect_session(host: &str, port: u16, config: &json::JsonValue) {
+    let connect = shell::Connect::new(host, port, config.clone());
+    if let Err(e) = connect.run_with_callback(|session_id: usize| {
+        println!("[*] Session {} created", session_id);
+    }) {
+        println!("[!] Failed to connect to {}:{}: {}", host, port, e);
+    }
+}
+
 fn kill_session(id: usize) {
     let result = crate::session::SESSION_MANAGER.kill_session(id);
     if result {
@@ -237,14 +752,225 @@ fn kill_session(id: usize) {
     }
 }
 
+const PAYLOADS_USAGE: &str =
+    "Usage: payloads [shell] [--advertise-port N] [--encode X] [--os X] [--requires a,b] [--tag X] [--respawn] [--note text...]";
+
+/// `payloads [shell] [--advertise-port N] [--encode X] [filters]`: re-render
+/// templates against the running listener. `--advertise-port` only changes
+/// the port payloads call back to; it doesn't start another listener.
+fn payloads_command(cmd: &str, config: &json::JsonValue, listening: &mut Listening) {
+    let mut shell = listening.shell.as_str();
+    let mut endpoints = listening.endpoints.clone();
+    let mut listing = Listing::default();
+
+    // the note is free text, so it takes the rest of the line
+    let cmd = match cmd.split_once(" --note ") {
+        Some((cmd, note)) => {
+            listing.note = Some(note.trim());
+            cmd
+        }
+        None => cmd,
+    };
+
+    let mut parts = cmd.split_whitespace().skip(1);
+    while let Some(part) = parts.next() {
+        if part == "--advertise-port" {
+            match parts.next().and_then(|p| p.parse::<u16>().ok()) {
+                Some(port) => endpoints.advertise_port = port,
+                None => {
+                    println!("Invalid port");
+                    return;
+                }
+            }
+            continue;
+        }
+        if part == "--respawn" {
+            listing.respawn = true;
+            continue;
+        }
+        if !part.starts_with("--") {
+            shell = part;
+            continue;
+        }
+        let Some(value) = parts.next() else {
+            println!("{}", PAYLOADS_USAGE);
+            return;
+        };
+        match part {
+            "--encode" => listing.encoding = Some(value),
+            "--os" => listing.filter.os = Some(value.to_string()),
+            "--requires" => listing.filter.requires = value.split(',').map(|r| r.to_string()).collect(),
+            "--tag" => listing.filter.tag = Some(value.to_string()),
+            _ => {
+                println!("{}", PAYLOADS_USAGE);
+                return;
+            }
+        }
+    }
+
+    // --advertise-port is for ports forwarded to the listener; nothing else answers
+    if endpoints.advertise_port != listening.endpoints.advertise_port {
+        println!(
+            "⚠️  These payloads call back to port {}, but the listener is on {}; forward {} to it or they won't connect\n",
+            endpoints.advertise_port, listening.endpoints.advertise_port, endpoints.advertise_port
+        );
+    }
+    match print_payloads(config, shell, &endpoints, listening.token.as_deref(), &listing) {
+        Ok(printed) if printed.is_empty() => println!("No templates match"),
+        Ok(printed) => listening.payloads = printed,
+        Err(e) => println!("⚠️  {}", e),
+    }
+}
+
+/// `copy <n>`: put payload <n> from the last listing on the clipboard
+fn copy_payload(cmd: &str, payloads: &[String]) {
+    let Some(n) = cmd.split_whitespace().nth(1).and_then(|n| n.parse::<usize>().ok()) else {
+        println!("Usage: copy <n>");
+        return;
+    };
+    match n.checked_sub(1).and_then(|i| payloads.get(i)) {
+        Some(command) => match helpers::osc52_copy(command) {
+            Ok(()) => println!("ℹ️  Payload {} copied to clipboard", n),
+            Err(e) => println!("⚠️  Failed to copy: {}", e),
+        },
+        None => println!("No payload {} (run `payloads` to list them)", n),
+    }
+}
+
+/// `pick [shell]`: browse templates in the payload picker
+fn pick_command(cmd: &str, config: &json::JsonValue, listening: &Listening) {
+    let shell = cmd.split_whitespace().nth(1).unwrap_or(&listening.shell);
+    let shell_obj = &config["shells"][shell];
+    if shell_obj.is_null() {
+        println!("⚠️  shell '{}' not found in config", shell);
+        return;
+    }
+    match resolve_encoding(None, shell_obj) {
+        Ok(encoding) => {
+            let vars = payload::Vars::new(shell, shell_obj, &listening.endpoints, listening.token.as_deref());
+            picker::pick_payload(shell_obj, &vars, encoding, listening.stager);
+        }
+        Err(e) => println!("⚠️  {}", e),
+    }
+}
+
+/// The requested encoding, else the shell's `encode` default, else raw
+fn resolve_encoding(name: Option<&str>, shell_obj: &json::JsonValue) -> Result<payload::Encoding, String> {
+    match name.or(shell_obj["encode"].as_str()) {
+        Some(name) => payload::Encoding::parse(name),
+        None => Ok(payload::Encoding::Raw),
+    }
+}
+
+/// How `print_payloads` lists templates
+#[derive(Default)]
+struct Listing<'a> {
+    /// Output encoding (default: the shell's `encode`)
+    encoding: Option<&'a str>,
+    filter: payload::Filter,
+    /// Recorded with each payload in the history
+    note: Option<&'a str>,
+    /// Print respawning variants instead
+    respawn: bool,
+}
+
+/// Render and print a shell's templates matching the listing's filter,
+/// numbered. Each one is recorded in the payload history (once; listing it
+/// again reuses the entry); when the listener enforces a token, each also
+/// gets a token of its own so sessions can be traced back to it. Returns the
+/// printed commands.
+fn print_payloads(
+    config: &json::JsonValue,
+    shell: &str,
+    endpoints: &config::Endpoints,
+    token: Option<&str>,
+    listing: &Listing,
+) -> Result<Vec<String>, String> {
+    let shell_obj = &config["shells"][shell];
+    if shell_obj.is_null() {
+        return Err(format!("shell '{}' not found in config", shell));
+    }
+    let encoding = resolve_encoding(listing.encoding, shell_obj)?;
+    let delay = payload::respawn_delay(config);
+    if !shell_obj["templates"].is_array() {
+        eprintln!("templates is missing or not an array for shell '{}'", shell);
+        return Ok(Vec::new());
+    }
+
+    let mut printed = Vec::new();
+    for (i, template) in payload::templates(shell_obj).iter().enumerate() {
+        if !listing.filter.matches(template) {
+            continue;
+        }
+        let issued = session::PAYLOAD_HISTORY.issue(shell, i + 1, listing.note, token.is_some());
+        let vars = payload::Vars::new(shell, shell_obj, endpoints, issued.token.as_deref().or(token));
+        let source = if listing.respawn { template.respawning(delay) } else { template.command.clone() };
+        let rendered = payload::render(&source, &vars);
+        let command = payload::encode(&rendered.command, encoding);
+        let summary = template.summary();
+        if summary.is_empty() {
+            println!("[{}] {}", printed.len() + 1, command);
+        } else {
+            println!("[{}] {}  # {}", printed.len() + 1, command, summary);
+        }
+        for warning in rendered.warnings {
+            println!("    ⚠️  {}", warning);
+        }
+        printed.push(command);
+    }
+    Ok(printed)
+}
+
+/// `shelly config check`: print every problem lint finds. Returns the exit code.
+fn config_check(config: &json::JsonValue) -> i32 {
+    let problems = lint::check(config);
+    for problem in &problems {
+        println!("⚠️  {}: {}", problem.path, problem.message);
+    }
+    if problems.is_empty() {
+        println!("ℹ️  No problems found in ~/.shelly/shelly.json");
+        0
+    } else {
+        println!("\n{} problem(s) found", problems.len());
+        1
+    }
+}
+
+/// Startup lint: problems with the shell in use in full, the rest counted
+fn report_problems(config: &json::JsonValue, shell: &str) {
+    let problems = lint::check(config);
+    let (ours, others): (Vec<_>, Vec<_>) = problems
+        .iter()
+        .partition(|p| p.shell.as_deref() == Some(shell));
+    for problem in &ours {
+        println!("⚠️  {}: {}", problem.path, problem.message);
+    }
+    if !others.is_empty() {
+        println!("⚠️  {} other problem(s) in shelly.json, see `shelly config check`", others.len());
+    }
+    if !problems.is_empty() {
+        println!();
+    }
+}
+
 fn print_help() {
     println!("Available commands:");
     println!("  sessions          - List all active sessions");
+    println!("  rejected          - List dropped connections: mux scanners, out of scope, bad tokens (UTC)");
+    println!("  history           - List issued payloads (UTC) that sessions are traced back to");
+    println!("  note <id> <text>  - Attach a note (target, exploit, ...) to payload <id>");
     println!("  switch <id>       - Switch to session <id>");
+    println!("  scrollback <id>   - Print the output kept for session <id>, across reconnects");
     println!("  kill <id>         - Kill session <id>");
+    println!("  connect <h> <p>   - Connect to a bind shell on <h>:<p>");
+    println!("  payloads [shell] [--advertise-port N] [--encode X] [--os X] [--requires a,b] [--tag X] [--respawn] [--note text...]");
+    println!("                    - Print numbered payloads for [shell] (default: current); --advertise-port");
+    println!("                      is a forwarded port to call back to, not a new listener");
+    println!("  copy <n>          - Copy payload <n> to the clipboard (OSC 52)");
+    println!("  pick [shell]      - Browse, encode and copy payloads in a menu");
     println!("  help              - Show this help message");
     println!("  quit/exit         - Exit shelly");
-    println!("");
+    println!();
     println!("Session commands (when in active session):");
     println!("  Ctrl+Z            - Background current session and return to shelly prompt");
 }
\ No newline at end of file
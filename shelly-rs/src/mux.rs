@@ -0,0 +1,549 @@
+use crate::config::Endpoints;
+use crate::serve::{Stager, StaticServer};
+use crate::shell;
+use dirs::home_dir;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a connection turned out to be, judged from its first bytes. Only
+/// the mux classifies connections; the other listeners take whatever
+/// connects first as the shell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Http,
+    Tls,
+    Shell,
+    /// Scanner or junk traffic that should never become a session
+    Junk(&'static str),
+}
+
+impl Protocol {
+    /// Classify a connection from the bytes peeked off the socket. Anything
+    /// that isn't HTTP, a TLS handshake or obvious scanner traffic is
+    /// treated as a shell.
+    pub fn detect(first: &[u8]) -> Self {
+        const METHODS: [&[u8]; 7] = [
+            b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ",
+        ];
+
+        // TLS record header: handshake (0x16), major version 3
+        if first.len() >= 2 && first[0] == 0x16 && first[1] == 0x03 {
+            return Protocol::Tls;
+        }
+        if METHODS.iter().any(|m| first.starts_with(m)) {
+            return Protocol::Http;
+        }
+        if first.starts_with(b"CONNECT ") || first.starts_with(b"PRI * HTTP/2") {
+            return Protocol::Junk("http proxy probe");
+        }
+        if first.starts_with(b"SSH-") {
+            return Protocol::Junk("ssh banner");
+        }
+        // shells talk text (plus terminal escapes); RDP, SMB and friends
+        // open with length fields full of control bytes
+        let binary = first
+            .iter()
+            .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x07 | 0x08 | 0x1b));
+        if binary {
+            return Protocol::Junk("binary probe");
+        }
+        Protocol::Shell
+    }
+}
+
+/// How long a client gets to speak first. Shells that stay silent until
+/// spoken to (e.g. `nc -e /bin/sh`) are routed as shells once it expires.
+const PEEK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// How long a TLS client gets to finish its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Poll interval for the TLS reader so writers can get at the stream
+const TLS_POLL: Duration = Duration::from_millis(50);
+
+/// Only one session can own the terminal at a time; later ones queue here.
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Single-port listener that serves shells, TLS shells and HTTP payload
+/// downloads side by side, routing each connection by its first bytes.
+pub struct Mux {
+    pub endpoints: Endpoints,
+    pub config: json::JsonValue,
+    pub token: Option<String>,
+    server: StaticServer,
+    ready: Option<Sender<()>>,
+}
+
+/// Everything a connection handler thread needs
+#[derive(Clone)]
+struct Route {
+    endpoints: Endpoints,
+    config: json::JsonValue,
+    server: StaticServer,
+    tls: Option<Arc<ServerConfig>>,
+    token: Option<String>,
+    token_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    created: Sender<usize>,
+}
+
+impl Mux {
+    pub fn new(
+        endpoints: Endpoints,
+        config: json::JsonValue,
+        files: Vec<(String, PathBuf)>,
+        token: Option<String>,
+        stager: bool,
+    ) -> Result<Self, String> {
+        let mut server = StaticServer::new(&endpoints.bind_host, endpoints.port, files)?;
+        if stager {
+            server = server.with_stager(Stager::new(config.clone(), endpoints.clone(), token.clone()));
+        }
+        Ok(Mux {
+            endpoints,
+            config,
+            token,
+            server,
+            ready: None,
+        })
+    }
+
+    /// Signal `ready` once the port is bound (TLS setup can take a while)
+    pub fn with_ready(mut self, ready: Sender<()>) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Run the multiplexed listener with a callback for each session established
+    pub fn run_with_callback<F>(&self, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        self.run_internal(Some(&mut callback))
+    }
+
+    fn run_internal<F>(&self, mut callback: Option<F>) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        // Setup signal handlers for session management
+        shell::setup_signal_handlers();
+
+        let tls = match tls_config(&self.config) {
+            Ok(c) => Some(Arc::new(c)),
+            Err(e) => {
+                eprintln!("TLS shells disabled: {}", e);
+                None
+            }
+        };
+
+        let port = self.endpoints.port;
+        let listener = crate::helpers::bind_tcp(&self.endpoints.bind_host, port)?;
+        if let Some(ref ready) = self.ready {
+            let _ = ready.send(());
+        }
+        eprintln!(
+            "listening on {} (shell/tls/http) ...",
+            crate::helpers::host_port(&self.endpoints.bind_host, port)
+        );
+
+        // handler threads report new sessions here so the callback runs on
+        // this thread; nonblocking accept lets us poll for them
+        let (tx, rx) = mpsc::channel::<usize>();
+        listener.set_nonblocking(true)?;
+
+        let route = Route {
+            endpoints: self.endpoints.clone(),
+            config: self.config.clone(),
+            server: self.server.clone(),
+            tls,
+            token: self.token.clone(),
+            token_timeout: crate::token::timeout(&self.config),
+            idle_timeout: self.config["shelly"]["idle_timeout"]
+                .as_u64()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            created: tx,
+        };
+
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    stream.set_nonblocking(false)?;
+                    // dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d
+                    let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+                    let route = route.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, peer, &route) {
+                            eprintln!("[!] Connection from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => return Err(e),
+            }
+
+            while let Ok(session_id) = rx.try_recv() {
+                if let Some(ref mut cb) = callback {
+                    cb(session_id);
+                }
+            }
+        }
+    }
+}
+
+/// Peek at the first bytes of a connection. Waits for the first byte as
+/// long as the socket's read timeout allows, then up to PEEK_TIMEOUT more
+/// for `buf` to fill, so a first segment split across packets isn't
+/// classified on a few bytes. Returns 0 if the peer closed without data.
+fn peek_first(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = stream.peek(buf)?;
+    let deadline = Instant::now() + PEEK_TIMEOUT;
+    // peek returns whatever is buffered straight away, so poll for more
+    while n > 0 && n < buf.len() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+        n = stream.peek(buf)?;
+    }
+    Ok(n)
+}
+
+fn handle_connection(stream: TcpStream, peer: SocketAddr, route: &Route) -> io::Result<()> {
+    let mut first = [0u8; 8];
+    // with idle filtering on, a connection that stays silent for the whole
+    // window is junk; otherwise it's a shell waiting to be spoken to
+    stream.set_read_timeout(Some(route.idle_timeout.unwrap_or(PEEK_TIMEOUT)))?;
+    let protocol = match peek_first(&stream, &mut first) {
+        Ok(0) => Protocol::Junk("closed without sending data"),
+        Ok(n) => Protocol::detect(&first[..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            if route.idle_timeout.is_some() {
+                Protocol::Junk("idle, never sent a prompt")
+            } else {
+                Protocol::Shell
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    // HTTP is checked by the file server itself
+    if matches!(protocol, Protocol::Shell | Protocol::Tls)
+        && !crate::scope::allow(peer.ip(), &format!("mux:{}", route.endpoints.port))
+    {
+        shell::reject_connection(peer, route.endpoints.port, "out of scope");
+        return Ok(());
+    }
+
+    match protocol {
+        Protocol::Junk(reason) => {
+            shell::reject_connection(peer, route.endpoints.port, reason);
+        }
+        Protocol::Http => {
+            stream.set_read_timeout(None)?;
+            // keep the request line around so probes can be logged
+            let mut head = [0u8; 256];
+            let n = stream.peek(&mut head)?;
+            let request_line = String::from_utf8_lossy(&head[..n])
+                .lines()
+                .next()
+                .unwrap_or("")
+                .to_string();
+            if !route.server.handle(stream).map_err(io::Error::other)? {
+                let reason = if crate::scope::in_scope(peer.ip()) {
+                    format!("http probe: {}", request_line)
+                } else {
+                    "out of scope".to_string()
+                };
+                shell::reject_connection(peer, route.endpoints.port, &reason);
+            }
+        }
+        Protocol::Tls => {
+            let Some(tls) = route.tls.clone() else {
+                shell::reject_connection(peer, route.endpoints.port, "tls probe (TLS disabled)");
+                return Ok(());
+            };
+            let socket = stream.try_clone()?;
+            let conn = ServerConnection::new(tls).map_err(io::Error::other)?;
+            let mut tls_stream = StreamOwned::new(conn, stream);
+            // a client stalling mid-handshake must not hold the thread forever
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            while tls_stream.conn.is_handshaking() {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    shell::reject_connection(peer, route.endpoints.port, "tls handshake timed out");
+                    return Ok(());
+                }
+                tls_stream.sock.set_read_timeout(Some(left))?;
+                if let Err(e) = tls_stream.conn.complete_io(&mut tls_stream.sock) {
+                    shell::reject_connection(peer, route.endpoints.port, &format!("tls handshake failed: {}", e));
+                    return Ok(());
+                }
+            }
+            let mut payload = None;
+            if let Some(ref token) = route.token {
+                tls_stream.sock.set_read_timeout(Some(route.token_timeout))?;
+                match crate::token::verify(&mut tls_stream, token) {
+                    Ok(matched) => payload = matched,
+                    Err(reason) => {
+                        shell::reject_connection(peer, route.endpoints.port, &reason);
+                        return Ok(());
+                    }
+                }
+            }
+            tls_stream.sock.set_read_timeout(Some(TLS_POLL))?;
+
+            let peer_stream = TlsPeer(Arc::new(Mutex::new(tls_stream)));
+            run_session("tls", peer, payload, peer_stream.clone(), peer_stream, socket, route)?;
+        }
+        Protocol::Shell => {
+            stream.set_read_timeout(None)?;
+            let mut payload = None;
+            if let Some(ref token) = route.token {
+                stream.set_read_timeout(Some(route.token_timeout))?;
+                match crate::token::verify(&mut &stream, token) {
+                    Ok(matched) => payload = matched,
+                    Err(reason) => {
+                        shell::reject_connection(peer, route.endpoints.port, &reason);
+                        return Ok(());
+                    }
+                }
+                stream.set_read_timeout(None)?;
+            }
+            let reader = stream.try_clone()?;
+            let socket = stream.try_clone()?;
+            run_session("tcp", peer, payload, reader, stream, socket, route)?;
+        }
+    }
+    Ok(())
+}
+
+/// Register a session (or reattach a reconnect), wait for the terminal and
+/// hand it to the shared pipeline. `payload` is the history id whose token
+/// the peer sent, if any.
+fn run_session<R, W>(
+    session_type: &str,
+    peer: SocketAddr,
+    payload: Option<usize>,
+    reader: R,
+    writer: W,
+    socket: TcpStream,
+    route: &Route,
+) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    eprintln!("connect to [{}] from {}", route.endpoints.bind_host, peer);
+    let (session_id, connection) = crate::session::SESSION_MANAGER.open_session(
+        session_type,
+        route.endpoints.port,
+        &peer.ip().to_string(),
+        crate::session::PAYLOAD_HISTORY.attribute(payload, peer.ip()),
+        crate::session::merge_reconnects(&route.config),
+    );
+    if connection > 0 {
+        eprintln!("[*] Session {} reconnected", session_id);
+    } else {
+        let _ = route.created.send(session_id);
+    }
+
+    let _terminal = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+    shell::attach(session_id, connection, reader, writer, Some(socket), &route.config, &route.endpoints)?;
+
+    // Deactivate session when connection ends, unless the target already
+    // called back into it
+    crate::session::SESSION_MANAGER.end_connection(session_id, connection);
+    Ok(())
+}
+
+/// A TLS stream shared between the pipeline's reader thread and writer.
+/// The socket has a short read timeout so the reader periodically releases
+/// the lock instead of starving writes.
+#[derive(Clone)]
+struct TlsPeer(Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>);
+
+impl Read for TlsPeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let result = self.0.lock().unwrap_or_else(|e| e.into_inner()).read(buf);
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+impl Write for TlsPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+/// Load the TLS identity for encrypted shells. Paths come from
+/// `shelly.tls_cert` / `shelly.tls_key`; without them a self-signed pair
+/// is generated with openssl under ~/.shelly/tls on first use.
+fn tls_config(config: &json::JsonValue) -> Result<ServerConfig, String> {
+    let home = home_dir().expect("Could not determine home directory");
+    let tls_dir = PathBuf::from(&home).join(".shelly").join("tls");
+
+    let (cert_path, key_path) = match (
+        config["shelly"]["tls_cert"].as_str(),
+        config["shelly"]["tls_key"].as_str(),
+    ) {
+        (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+        _ => {
+            let cert = tls_dir.join("cert.pem");
+            let key = tls_dir.join("key.pem");
+            if !cert.exists() || !key.exists() {
+                generate_self_signed(&cert, &key)?;
+            }
+            (cert, key)
+        }
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .map_err(|e| format!("failed to read {}: {}", cert_path.display(), e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("failed to parse {}: {}", cert_path.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| format!("failed to read {}: {}", key_path.display(), e))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("failed to configure TLS: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("failed to configure TLS: {}", e))
+}
+
+fn generate_self_signed(cert: &PathBuf, key: &PathBuf) -> Result<(), String> {
+    if let Some(parent) = cert.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+    }
+    println!("ℹ️  Generating self-signed TLS certificate in {}", cert.display());
+    let status = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "365"])
+        .args(["-subj", "/CN=shelly"])
+        .arg("-keyout")
+        .arg(key)
+        .arg("-out")
+        .arg(cert)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| format!("failed to run openssl: {}", e))?;
+    if !status.success() {
+        return Err(format!("openssl exited: {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Protocol, peek_first};
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    /// The server side of a loopback connection whose client runs `client`
+    fn serve_client(client: impl FnOnce(TcpStream) + Send + 'static) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || client(TcpStream::connect(addr).unwrap()));
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    #[test]
+    fn detects_http() {
+        assert_eq!(Protocol::detect(b"GET /soc"), Protocol::Http);
+        assert_eq!(Protocol::detect(b"POST /s/"), Protocol::Http);
+        assert_eq!(Protocol::detect(b"OPTIONS "), Protocol::Http);
+    }
+
+    #[test]
+    fn detects_tls() {
+        assert_eq!(Protocol::detect(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01]), Protocol::Tls);
+        // SSLv2-style or non-handshake records aren't TLS shells
+        assert_eq!(Protocol::detect(&[0x17, 0x03, 0x03]), Protocol::Junk("binary probe"));
+    }
+
+    #[test]
+    fn detects_junk() {
+        assert_eq!(Protocol::detect(b"SSH-2.0-"), Protocol::Junk("ssh banner"));
+        assert_eq!(Protocol::detect(b"CONNECT "), Protocol::Junk("http proxy probe"));
+        assert_eq!(Protocol::detect(b"PRI * HTTP/2"), Protocol::Junk("http proxy probe"));
+        // RDP connection request
+        assert_eq!(Protocol::detect(&[0x03, 0x00, 0x00, 0x13, 0x0e, 0xe0]), Protocol::Junk("binary probe"));
+    }
+
+    #[test]
+    fn detects_shells() {
+        assert_eq!(Protocol::detect(b"root@box"), Protocol::Shell);
+        assert_eq!(Protocol::detect(b"$ "), Protocol::Shell);
+        assert_eq!(Protocol::detect(b"\x1b[?2004h"), Protocol::Shell);
+        assert_eq!(Protocol::detect(b"sh: 0: \r\n"), Protocol::Shell);
+    }
+
+    #[test]
+    fn short_reads() {
+        // a request split across packets is waited for, not classified on
+        // its first two bytes
+        let stream = serve_client(|mut client| {
+            client.write_all(b"GE").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            client.write_all(b"T /socat HTTP/1.1\r\n").unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        let mut first = [0u8; 8];
+        let n = peek_first(&stream, &mut first).unwrap();
+        assert_eq!(&first[..n], b"GET /soc");
+        assert_eq!(Protocol::detect(&first[..n]), Protocol::Http);
+
+        let stream = serve_client(|mut client| {
+            client.write_all(&[0x16]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            client.write_all(&[0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01]).unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        let n = peek_first(&stream, &mut first).unwrap();
+        assert_eq!(Protocol::detect(&first[..n]), Protocol::Tls);
+    }
+
+    #[test]
+    fn short_prompts() {
+        // a prompt that never reaches 8 bytes is classified once the wait is up
+        let stream = serve_client(|mut client| {
+            client.write_all(b"$ ").unwrap();
+            thread::sleep(Duration::from_secs(3));
+        });
+        let mut first = [0u8; 8];
+        let n = peek_first(&stream, &mut first).unwrap();
+        assert_eq!(&first[..n], b"$ ");
+        assert_eq!(Protocol::detect(&first[..n]), Protocol::Shell);
+
+        let stream = serve_client(drop);
+        assert_eq!(peek_first(&stream, &mut first).unwrap(), 0);
+    }
+}
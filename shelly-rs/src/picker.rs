@@ -0,0 +1,175 @@
+use crate::helpers;
+use crate::payload::{self, Encoding, Vars};
+use crate::serve::Stager;
+use crate::session::PAYLOAD_HISTORY;
+use terminal_menu::{back_button, button, label, menu, mut_menu, run};
+
+/// Width preview lines are wrapped at
+const PREVIEW_WIDTH: usize = 76;
+
+/// Longest template shown in the template list before it's cut short
+const LIST_WIDTH: usize = 60;
+
+/// Menu of the shells in config, used when no shell is given on the
+/// command line. Returns None if the menu is canceled.
+pub fn pick_shell(config: &json::JsonValue) -> Option<String> {
+    let shells: Vec<(String, String)> = config["shells"]
+        .entries()
+        .map(|(name, obj)| {
+            let listener = obj["listener"].as_str().unwrap_or("netcat");
+            let count = payload::templates(obj).len();
+            (name.to_string(), format!("{:<16} {:<10} {} template(s)", name, listener, count))
+        })
+        .collect();
+    if shells.is_empty() {
+        eprintln!("⚠️  No shells in config");
+        return None;
+    }
+
+    let mut menu_items = vec![label("Select Shell")];
+    for (_, line) in &shells {
+        menu_items.push(button(line.clone()));
+    }
+
+    let menu = menu(menu_items);
+    run(&menu);
+
+    if mut_menu(&menu).canceled() {
+        println!("Canceled!");
+        return None;
+    }
+
+    // index 0 is the label
+    Some(shells[mut_menu(&menu).selected_item_index() - 1].0.clone())
+}
+
+/// Actions offered under a payload preview
+enum Action {
+    Copy,
+    Print,
+    Encoding,
+    Stager,
+    Back,
+}
+
+/// Browse a shell's templates: pick one, preview it rendered, switch its
+/// encoding or (with `stager` on) swap it for a one-liner fetching it from
+/// `/s/<shell>/<n>`, then copy it to the clipboard or print it. Esc in the
+/// template list leaves the picker.
+pub fn pick_payload(shell_obj: &json::JsonValue, vars: &Vars, mut encoding: Encoding, stager: bool) {
+    let templates = payload::templates(shell_obj);
+    if templates.is_empty() {
+        println!("No templates for shell '{}'", vars.shell);
+        return;
+    }
+
+    loop {
+        let mut menu_items = vec![label("Select Payload (Esc when done)")];
+        for template in &templates {
+            let summary = template.summary();
+            let text = if summary.is_empty() { &template.command } else { &summary };
+            menu_items.push(button(truncate(text, LIST_WIDTH)));
+        }
+
+        let list = menu(menu_items);
+        run(&list);
+        if mut_menu(&list).canceled() {
+            return;
+        }
+        let number = mut_menu(&list).selected_item_index();
+        let template = &templates[number - 1];
+        let mut staged = false;
+
+        // a previewed payload is as good as printed: record it and give it
+        // its own token like a listed one
+        let issued = PAYLOAD_HISTORY.issue(&vars.shell, number, None, vars.token.is_some());
+        let mut vars = vars.clone();
+        vars.token = issued.token.or(vars.token);
+        let vars = &vars;
+
+        // action menu, re-shown with a fresh preview after an encoding change
+        loop {
+            let rendered = payload::render(&template.command, vars);
+            let command = if staged {
+                payload::encode(&Stager::command(vars, shell_obj, Some(number)), encoding)
+            } else {
+                payload::encode(&rendered.command, encoding)
+            };
+
+            let mut menu_items = vec![label("Payload")];
+            for chunk in preview_lines(&command) {
+                menu_items.push(label(chunk));
+            }
+            for warning in &rendered.warnings {
+                menu_items.push(label(format!("⚠️  {}", warning)));
+            }
+            menu_items.push(label(""));
+            let first_action = menu_items.len();
+            let mut actions = vec![Action::Copy, Action::Print, Action::Encoding];
+            menu_items.push(button("Copy to clipboard"));
+            menu_items.push(button("Print"));
+            menu_items.push(button(format!("Encoding: {}", encoding.name())));
+            if stager {
+                actions.push(Action::Stager);
+                menu_items.push(button(if staged { "Serve as stager: on" } else { "Serve as stager: off" }));
+            }
+            actions.push(Action::Back);
+            menu_items.push(back_button("Back"));
+
+            let action_menu = menu(menu_items);
+            mut_menu(&action_menu).set_selected_item_with_index(first_action);
+            run(&action_menu);
+            if mut_menu(&action_menu).canceled() {
+                break;
+            }
+
+            match actions[mut_menu(&action_menu).selected_item_index() - first_action] {
+                Action::Copy => {
+                    match helpers::osc52_copy(&command) {
+                        Ok(()) => println!("ℹ️  Payload copied to clipboard"),
+                        Err(e) => println!("⚠️  Failed to copy: {}", e),
+                    }
+                    return;
+                }
+                Action::Print => {
+                    println!("{}", command);
+                    return;
+                }
+                Action::Encoding => encoding = pick_encoding(encoding),
+                Action::Stager => staged = !staged,
+                Action::Back => break,
+            }
+        }
+    }
+}
+
+/// Small menu of the output encodings, starting on the current one
+fn pick_encoding(current: Encoding) -> Encoding {
+    let mut menu_items = vec![label("Encoding")];
+    for encoding in Encoding::ALL {
+        menu_items.push(button(encoding.name()));
+    }
+
+    let menu = menu(menu_items);
+    mut_menu(&menu).set_selected_item_with_name(current.name());
+    run(&menu);
+
+    if mut_menu(&menu).canceled() {
+        return current;
+    }
+    Encoding::ALL[mut_menu(&menu).selected_item_index() - 1]
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        format!("{}...", text.chars().take(width - 3).collect::<String>())
+    }
+}
+
+/// Split a command into lines that fit the menu
+fn preview_lines(command: &str) -> Vec<String> {
+    let chars: Vec<char> = command.chars().collect();
+    chars.chunks(PREVIEW_WIDTH).map(|c| c.iter().collect()).collect()
+}
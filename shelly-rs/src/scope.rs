@@ -0,0 +1,163 @@
+use crate::helpers;
+use dirs::home_dir;
+use pnet::ipnetwork::IpNetwork;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What to do with a connection from outside the engagement scope
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Let it through, but make noise about it
+    Warn,
+    /// Drop it before it reaches a session or a served file
+    Refuse,
+}
+
+/// The engagement scope from the `scope` section of shelly.json:
+///
+/// ```json
+/// "scope": { "mode": "refuse", "targets": ["10.10.10.0/24", "dc01.corp.local"] }
+/// ```
+pub struct Scope {
+    pub mode: Mode,
+    pub networks: Vec<IpNetwork>,
+}
+
+static SCOPE: OnceLock<Option<Scope>> = OnceLock::new();
+
+/// Serialises audit log appends from listener and server threads
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+impl Scope {
+    /// Build the scope from config. Returns None when no targets are listed,
+    /// which leaves every listener unrestricted.
+    pub fn from_config(config: &json::JsonValue) -> Option<Scope> {
+        let section = &config["scope"];
+        let mode = match section["mode"].as_str().unwrap_or("warn") {
+            "refuse" => Mode::Refuse,
+            "warn" => Mode::Warn,
+            other => {
+                eprintln!("⚠️  Unknown scope mode '{}', using 'warn'", other);
+                Mode::Warn
+            }
+        };
+
+        let mut networks = Vec::new();
+        for target in section["targets"].members().filter_map(|t| t.as_str()) {
+            if let Ok(net) = target.parse::<IpNetwork>() {
+                networks.push(net);
+                continue;
+            }
+            // not an address or CIDR, so treat it as a hostname
+            match (target, 0).to_socket_addrs() {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                        if let Ok(net) = IpNetwork::new(addr.ip(), prefix) {
+                            networks.push(net);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("⚠️  Could not resolve scope target '{}': {}", target, e),
+            }
+        }
+
+        if networks.is_empty() {
+            None
+        } else {
+            Some(Scope { mode, networks })
+        }
+    }
+
+    /// Loopback is always in scope; it is the operator's own machine
+    /// (and where port forwards land).
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_loopback() || self.networks.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Load the scope once at startup
+pub fn init(config: &json::JsonValue) {
+    SCOPE.get_or_init(|| Scope::from_config(config));
+}
+
+/// One-line description of the active scope for the startup banner
+pub fn summary() -> Option<String> {
+    let Some(Some(scope)) = SCOPE.get() else {
+        return None;
+    };
+    Some(format!(
+        "Engagement scope: {} network(s), out-of-scope connections will be {}",
+        scope.networks.len(),
+        if scope.mode == Mode::Refuse { "refused" } else { "flagged" }
+    ))
+}
+
+/// Whether a scope is being enforced at all
+pub fn is_active() -> bool {
+    matches!(SCOPE.get(), Some(Some(_)))
+}
+
+/// Whether `ip` is in scope (or no scope is set), without auditing
+pub fn in_scope(ip: IpAddr) -> bool {
+    match SCOPE.get() {
+        Some(Some(scope)) => scope.contains(ip),
+        _ => true,
+    }
+}
+
+/// Decide whether `ip` may proceed in `context` (e.g. "mux:4444"), writing
+/// the decision to the audit log. Out-of-scope addresses are allowed with a
+/// loud warning in warn mode and refused in refuse mode.
+pub fn allow(ip: IpAddr, context: &str) -> bool {
+    let Some(Some(scope)) = SCOPE.get() else {
+        return true;
+    };
+
+    if scope.contains(ip) {
+        audit("ALLOW", ip, context);
+        return true;
+    }
+
+    match scope.mode {
+        Mode::Warn => {
+            audit("WARN", ip, context);
+            eprintln!(
+                "\n⚠️  ⚠️  OUT OF SCOPE: {} on {} is not in the engagement scope ⚠️  ⚠️\n",
+                ip, context
+            );
+            true
+        }
+        Mode::Refuse => {
+            audit("REFUSE", ip, context);
+            false
+        }
+    }
+}
+
+/// Append a decision to ~/.shelly/audit.log
+fn audit(decision: &str, ip: IpAddr, context: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let line = format!("{} {} {} {}\n", helpers::utc_timestamp(now), decision, ip, context);
+
+    let home = home_dir().expect("Could not determine home directory");
+    let audit_path = PathBuf::from(&home).join(".shelly").join("audit.log");
+
+    let _guard = AUDIT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&audit_path)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Failed to write audit log {}: {}", audit_path.display(), e);
+    }
+}
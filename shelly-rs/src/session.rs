@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -6,11 +7,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub struct Session {
     pub id: usize,
     pub session_type: String, // "netcat", "socat", etc.
+    #[allow(dead_code)]
     pub created: u64, // timestamp
     pub last_activity: u64,
     pub is_active: bool,
     pub listener_port: u16,
     pub target_ip: String,
+    /// Payload history id this session was traced back to
+    pub payload: Option<usize>,
+    /// Times the target called back into this session after dropping
+    pub reconnects: usize,
 }
 
 impl Session {
@@ -28,6 +34,8 @@ impl Session {
             is_active: true,
             listener_port,
             target_ip: target_ip.to_string(),
+            payload: None,
+            reconnects: 0,
         }
     }
     
@@ -42,11 +50,28 @@ impl Session {
     pub fn deactivate(&mut self) {
         self.is_active = false;
     }
+
+    /// Whether a new connection is this (dropped) session calling back:
+    /// same listener, and the same payload when both are known, else the
+    /// same host
+    fn is_reconnect(&self, session_type: &str, listener_port: u16, target_ip: &str, payload: Option<usize>) -> bool {
+        if self.is_active || self.session_type != session_type || self.listener_port != listener_port {
+            return false;
+        }
+        match (self.payload, payload) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => self.target_ip == target_ip,
+        }
+    }
 }
 
+/// Most output kept per session for `scrollback`
+const MAX_SCROLLBACK: usize = 64 * 1024;
+
 pub struct SessionManager {
     sessions: Arc<Mutex<HashMap<usize, Session>>>,
     next_id: Arc<Mutex<usize>>,
+    scrollback: Arc<Mutex<HashMap<usize, Vec<u8>>>>,
 }
 
 impl SessionManager {
@@ -54,6 +79,7 @@ impl SessionManager {
         SessionManager {
             sessions: Arc::new(Mutex::new(HashMap::new())),
             next_id: Arc::new(Mutex::new(1)),
+            scrollback: Arc::new(Mutex::new(HashMap::new())),
         }
     }
     
@@ -69,6 +95,43 @@ impl SessionManager {
         
         id
     }
+
+    /// Register a connection, reattaching it to a dropped session it is a
+    /// reconnect of (see `Session::is_reconnect`) so the id, scrollback and
+    /// metadata carry over. Returns the id and which connection of the
+    /// session this is (0 for a new session).
+    pub fn open_session(
+        &self,
+        session_type: &str,
+        listener_port: u16,
+        target_ip: &str,
+        payload: Option<usize>,
+        merge: bool,
+    ) -> (usize, usize) {
+        if merge {
+            let mut sessions = self.sessions.lock().unwrap();
+            // the most recent one wins if a host dropped several
+            let previous = sessions
+                .values_mut()
+                .filter(|s| s.is_reconnect(session_type, listener_port, target_ip, payload))
+                .max_by_key(|s| s.id);
+            if let Some(session) = previous {
+                session.is_active = true;
+                session.reconnects += 1;
+                session.update_activity();
+                if payload.is_some() {
+                    session.payload = payload;
+                }
+                return (session.id, session.reconnects);
+            }
+        }
+
+        let id = self.create_session(session_type, listener_port, target_ip);
+        if let Some(payload) = payload {
+            self.set_payload(id, payload);
+        }
+        (id, 0)
+    }
     
     pub fn get_session(&self, id: usize) -> Option<Session> {
         let sessions = self.sessions.lock().unwrap();
@@ -89,37 +152,379 @@ impl SessionManager {
         }
     }
     
+    /// Deactivate a session unless it has been reattached since `connection`
+    /// (as returned by `open_session`) was opened
+    pub fn end_connection(&self, id: usize, connection: usize) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&id)
+            && session.reconnects == connection
+        {
+            session.deactivate();
+        }
+    }
+
+    pub fn set_payload(&self, id: usize, payload: usize) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&id) {
+            session.payload = Some(payload);
+        }
+    }
+
     pub fn list_sessions(&self) -> Vec<Session> {
         let sessions = self.sessions.lock().unwrap();
         sessions.values().cloned().collect()
     }
     
     pub fn kill_session(&self, id: usize) -> bool {
+        self.scrollback.lock().unwrap().remove(&id);
         let mut sessions = self.sessions.lock().unwrap();
-        if sessions.remove(&id).is_some() {
-            true
-        } else {
-            false
+        sessions.remove(&id).is_some()
+    }
+
+    /// Keep remote output for `scrollback`, dropping the oldest bytes past
+    /// MAX_SCROLLBACK
+    pub fn append_output(&self, id: usize, output: &[u8]) {
+        let mut scrollback = self.scrollback.lock().unwrap();
+        let buffer = scrollback.entry(id).or_default();
+        buffer.extend_from_slice(output);
+        if buffer.len() > MAX_SCROLLBACK {
+            let excess = buffer.len() - MAX_SCROLLBACK;
+            buffer.drain(..excess);
         }
     }
+
+    pub fn scrollback(&self, id: usize) -> Vec<u8> {
+        let scrollback = self.scrollback.lock().unwrap();
+        scrollback.get(&id).cloned().unwrap_or_default()
+    }
     
+    #[allow(dead_code)]
     pub fn get_next_id(&self) -> usize {
         let next_id = self.next_id.lock().unwrap();
         *next_id
     }
     
     // Public methods to access the internal locks
-    pub fn lock_sessions(&self) -> std::sync::MutexGuard<std::collections::HashMap<usize, Session>> {
+    #[allow(dead_code)]
+    pub fn lock_sessions(&self) -> std::sync::MutexGuard<'_, std::collections::HashMap<usize, Session>> {
         self.sessions.lock().unwrap()
     }
     
-    pub fn lock_next_id(&self) -> std::sync::MutexGuard<usize> {
+    #[allow(dead_code)]
+    pub fn lock_next_id(&self) -> std::sync::MutexGuard<'_, usize> {
         self.next_id.lock().unwrap()
     }
 }
 
+/// Most rejected connections we keep; scanners on public listeners add up
+const MAX_REJECTED: usize = 1000;
+
+/// A connection that was dropped before it became a session
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub time: u64, // timestamp
+    pub peer: String,
+    pub listener_port: u16,
+    pub reason: String,
+}
+
+pub struct RejectedLog {
+    entries: Arc<Mutex<Vec<Rejected>>>,
+}
+
+impl RejectedLog {
+    pub fn new() -> Self {
+        RejectedLog {
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn record(&self, peer: &str, listener_port: u16, reason: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_REJECTED {
+            entries.remove(0);
+        }
+        entries.push(Rejected {
+            time: now,
+            peer: peer.to_string(),
+            listener_port,
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn list(&self) -> Vec<Rejected> {
+        let entries = self.entries.lock().unwrap();
+        entries.clone()
+    }
+}
+
+/// A payload printed or staged for the operator, kept so sessions can be
+/// traced back to it
+#[derive(Debug, Clone)]
+pub struct Issued {
+    pub id: usize,
+    pub time: u64, // timestamp
+    pub shell: String,
+    pub template: usize, // 1-based, as listed
+    /// Token only this payload sends, when the listener enforces tokens
+    pub token: Option<String>,
+    pub note: Option<String>,
+    /// Who fetched it, for stager scripts
+    pub fetched_by: Option<IpAddr>,
+}
+
+impl Issued {
+    /// `p3 bash#1 (note)`
+    pub fn label(&self) -> String {
+        match &self.note {
+            Some(note) => format!("p{} {}#{} ({})", self.id, self.shell, self.template, note),
+            None => format!("p{} {}#{}", self.id, self.shell, self.template),
+        }
+    }
+}
+
+pub struct PayloadHistory {
+    entries: Arc<Mutex<Vec<Issued>>>,
+}
+
+impl PayloadHistory {
+    pub fn new() -> Self {
+        PayloadHistory {
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Record an issued payload and return its id
+    pub fn record(
+        &self,
+        shell: &str,
+        template: usize,
+        token: Option<String>,
+        note: Option<String>,
+        fetched_by: Option<IpAddr>,
+    ) -> usize {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.len() + 1;
+        entries.push(Issued {
+            id,
+            time: now,
+            shell: shell.to_string(),
+            template,
+            token,
+            note,
+            fetched_by,
+        });
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<Issued> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(id.checked_sub(1)?).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Issued> {
+        let entries = self.entries.lock().unwrap();
+        entries.clone()
+    }
+
+    pub fn set_note(&self, id: usize, note: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match id.checked_sub(1).and_then(|i| entries.get_mut(i)) {
+            Some(entry) => {
+                entry.note = Some(note.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// An earlier listing of the same template with the same note, so
+    /// listing it again reuses that entry (and its token)
+    pub fn issued(&self, shell: &str, template: usize, note: Option<&str>) -> Option<Issued> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .find(|e| e.fetched_by.is_none() && e.shell == shell && e.template == template && e.note.as_deref() == note)
+            .cloned()
+    }
+
+    /// The entry to hand out for `template` of `shell` with `note`: an
+    /// earlier listing of it (see `issued`), else a newly recorded one with
+    /// a fresh token of its own when the listener enforces tokens (`tokens`).
+    /// Printed listings and the picker both go through here.
+    pub fn issue(&self, shell: &str, template: usize, note: Option<&str>, tokens: bool) -> Issued {
+        if let Some(entry) = self.issued(shell, template, note) {
+            return entry;
+        }
+        let token = tokens.then(crate::token::generate);
+        let id = self.record(shell, template, token, note.map(|n| n.to_string()), None);
+        self.get(id).expect("just recorded")
+    }
+
+    /// The payload whose own token is `token`
+    pub fn by_token(&self, token: &str) -> Option<usize> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|e| e.token.as_deref() == Some(token)).map(|e| e.id)
+    }
+
+    /// Which payload a new session from `peer` came from: the one whose token
+    /// it sent, else the latest stager it fetched
+    pub fn attribute(&self, token_match: Option<usize>, peer: IpAddr) -> Option<usize> {
+        if token_match.is_some() {
+            return token_match;
+        }
+        let entries = self.entries.lock().unwrap();
+        entries.iter().rev().find(|e| e.fetched_by == Some(peer)).map(|e| e.id)
+    }
+}
+
+/// Whether reconnects are folded into the session they dropped from
+/// (`shelly.merge_reconnects`, on by default)
+pub fn merge_reconnects(config: &json::JsonValue) -> bool {
+    config["shelly"]["merge_reconnects"].as_bool().unwrap_or(true)
+}
+
 // Default session manager instance
 lazy_static::lazy_static! {
     pub static ref SESSION_MANAGER: SessionManager = SessionManager::new();
+    pub static ref REJECTED_LOG: RejectedLog = RejectedLog::new();
+    pub static ref PAYLOAD_HISTORY: PayloadHistory = PayloadHistory::new();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const TARGET: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 10, 10, 5));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 10, 10, 6));
+
+    #[test]
+    fn finds_payloads_by_token() {
+        let history = PayloadHistory::new();
+        let first = history.record("bash", 1, Some("aaaa".to_string()), None, None);
+        let second = history.record("bash", 2, Some("bbbb".to_string()), None, None);
+        history.record("nc", 1, None, None, None);
+        assert_eq!(history.by_token("aaaa"), Some(first));
+        assert_eq!(history.by_token("bbbb"), Some(second));
+        assert_eq!(history.by_token("cccc"), None);
+    }
+
+    #[test]
+    fn attributes_token_before_fetches() {
+        let history = PayloadHistory::new();
+        let listed = history.record("bash", 1, Some("aaaa".to_string()), None, None);
+        history.record("bash", 2, None, None, Some(TARGET));
+        let refetched = history.record("bash", 3, None, None, Some(TARGET));
+        assert_eq!(history.attribute(Some(listed), TARGET), Some(listed));
+        // else the latest stager the peer fetched
+        assert_eq!(history.attribute(None, TARGET), Some(refetched));
+        assert_eq!(history.attribute(None, OTHER), None);
+    }
+
+    #[test]
+    fn issues_each_listing_once() {
+        let history = PayloadHistory::new();
+        let first = history.issue("bash", 1, None, true);
+        assert_eq!(first.token.as_deref().map(str::len), Some(16));
+        // listing it again reuses the entry and its token
+        let again = history.issue("bash", 1, None, true);
+        assert_eq!((again.id, again.token), (first.id, first.token.clone()));
+        assert_ne!(history.issue("bash", 1, Some("dc01"), true).id, first.id);
+        // stager fetches are entries of their own
+        let fetched = history.record("bash", 2, None, None, Some(TARGET));
+        assert_ne!(history.issue("bash", 2, None, true).id, fetched);
+        assert_eq!(history.issue("nc", 1, None, false).token, None);
+        assert_eq!(history.by_token(first.token.as_deref().unwrap()), Some(first.id));
+    }
+
+    #[test]
+    fn reattaches_same_payload() {
+        let manager = SessionManager::new();
+        let (id, connection) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        assert_eq!(connection, 0);
+        manager.end_connection(id, connection);
+        // from another address, e.g. through a different pivot
+        assert_eq!(manager.open_session("mux", 4444, "10.10.10.6", Some(1), true), (id, 1));
+        let session = manager.get_session(id).unwrap();
+        assert!(session.is_active);
+        assert_eq!(session.reconnects, 1);
+        assert_eq!(session.target_ip, "10.10.10.5");
+    }
+
+    #[test]
+    fn different_payload_is_a_new_session() {
+        let manager = SessionManager::new();
+        let (id, connection) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        manager.end_connection(id, connection);
+        let (other, connection) = manager.open_session("mux", 4444, "10.10.10.5", Some(2), true);
+        assert_ne!(other, id);
+        assert_eq!(connection, 0);
+    }
+
+    #[test]
+    fn reattaches_same_target_ip() {
+        let manager = SessionManager::new();
+        let (id, connection) = manager.open_session("udp", 53, "10.10.10.5", None, true);
+        manager.end_connection(id, connection);
+        // a payload on only one side falls back to the host
+        assert_eq!(manager.open_session("udp", 53, "10.10.10.5", Some(3), true), (id, 1));
+        assert_eq!(manager.get_session(id).unwrap().payload, Some(3));
+        manager.end_connection(id, 1);
+        assert_ne!(manager.open_session("udp", 53, "10.10.10.6", None, true).0, id);
+        // nor across listeners
+        assert_ne!(manager.open_session("udp", 54, "10.10.10.5", None, true).0, id);
+    }
+
+    #[test]
+    fn active_sessions_are_not_reattached() {
+        let manager = SessionManager::new();
+        let (id, _) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        assert_ne!(manager.open_session("mux", 4444, "10.10.10.5", Some(1), true).0, id);
+    }
+
+    #[test]
+    fn merge_off() {
+        let manager = SessionManager::new();
+        let (id, connection) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), false);
+        manager.end_connection(id, connection);
+        let (other, connection) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), false);
+        assert_ne!(other, id);
+        assert_eq!(connection, 0);
+        assert!(!manager.get_session(id).unwrap().is_active);
+    }
+
+    #[test]
+    fn counts_reconnects() {
+        let manager = SessionManager::new();
+        let (id, _) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        for expected in 1..=3 {
+            manager.end_connection(id, expected - 1);
+            assert_eq!(manager.open_session("mux", 4444, "10.10.10.5", Some(1), true), (id, expected));
+        }
+        assert_eq!(manager.get_session(id).unwrap().reconnects, 3);
+    }
+
+    #[test]
+    fn stale_connection_keeps_session_active() {
+        let manager = SessionManager::new();
+        let (id, first) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        manager.end_connection(id, first);
+        let (_, second) = manager.open_session("mux", 4444, "10.10.10.5", Some(1), true);
+        // the old connection's reader noticing EOF late
+        manager.end_connection(id, first);
+        assert!(manager.get_session(id).unwrap().is_active);
+        manager.end_connection(id, second);
+        assert!(!manager.get_session(id).unwrap().is_active);
+    }
+}
